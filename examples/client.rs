@@ -1,8 +1,8 @@
 use kcp_rs::Kcp;
-use std::io::{self, Write};
+use std::cmp::min;
+use std::io::{self, ErrorKind, Write};
 use std::net::{SocketAddr, UdpSocket};
 use std::rc::Rc;
-use std::thread::sleep;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 struct KcpOutput {
@@ -12,7 +12,7 @@ struct KcpOutput {
 
 impl Write for KcpOutput {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.udp.send_to(buf, &self.peer)
+        self.udp.send_to(buf, self.peer)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -20,11 +20,16 @@ impl Write for KcpOutput {
     }
 }
 
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u32
+}
+
 fn main() {
     let socket = UdpSocket::bind("127.0.0.1:7070").expect("failed to bind host socket");
 
-    socket.set_nonblocking(true).unwrap();
-
     let ss = Rc::new(socket);
 
     let kcpo = KcpOutput {
@@ -35,46 +40,42 @@ fn main() {
     let mut kcp = Kcp::ickp_create(kcpo, 1);
     // kcp.ikcp_nodelay(true, 1, 10, true);
 
+    let mut next_send = now();
+
     loop {
-        let current = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u32;
-        kcp.ikcp_update(current);
+        let current = now();
 
-        kcp.ikcp_send(b"hello world").unwrap();
+        // 每 2 秒发送一次
+        if (current.wrapping_sub(next_send) as i32) >= 0 {
+            kcp.ikcp_send(b"hello world").unwrap();
+            next_send = current.wrapping_add(2000);
+        }
 
-        loop {
-            let mut ss_buf = [0; 100];
-            match ss.recv_from(&mut ss_buf) {
-                Ok((a, _b)) => {
-                    if a > 0 {
-                        kcp.ikcp_input(&ss_buf[..a]).unwrap();
-                    }
-                }
-                Err(_x) => {
-                    break;
-                }
+        if kcp.ikcp_check(current) == current {
+            if let Err(e) = kcp.ikcp_update(current) {
+                println!("update error-->{}", e);
             }
         }
 
-        loop {
-            let mut read_buf = [0; 100];
-            match kcp.ikcp_recv(&mut read_buf) {
-                Ok(x) => {
-                    if x > 0 {
-                        println!(
-                            "recive-->{:?}",
-                            String::from_utf8(read_buf[..x].to_vec()).unwrap()
-                        );
-                    }
-                }
-                Err(_x) => {
-                    break;
+        // 阻塞到下一次需要 update 或发送的时刻，期间 socket 可读则提前醒来
+        let next = kcp.ikcp_check(current);
+        let wait = min(next.wrapping_sub(current), next_send.wrapping_sub(current)).max(1);
+        ss.set_read_timeout(Some(Duration::from_millis(wait as u64)))
+            .unwrap();
+
+        let mut ss_buf = [0; 1500];
+        match ss.recv_from(&mut ss_buf) {
+            Ok((a, _b)) => {
+                if a > 0 {
+                    kcp.ikcp_input(&ss_buf[..a]).unwrap();
                 }
             }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => panic!("recv error-->{}", e),
         }
 
-        sleep(Duration::from_secs(2));
+        for message in kcp.messages() {
+            println!("recive-->{:?}", String::from_utf8(message).unwrap());
+        }
     }
 }
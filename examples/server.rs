@@ -1,8 +1,7 @@
 use kcp_rs::Kcp;
-use std::io::{self, Write};
+use std::io::{self, ErrorKind, Write};
 use std::net::{SocketAddr, UdpSocket};
 use std::rc::Rc;
-use std::thread::sleep;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 struct KcpOutput {
@@ -12,7 +11,7 @@ struct KcpOutput {
 
 impl Write for KcpOutput {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.udp.send_to(buf, &self.peer)
+        self.udp.send_to(buf, self.peer)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -20,9 +19,15 @@ impl Write for KcpOutput {
     }
 }
 
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u32
+}
+
 fn main() {
     let socket = UdpSocket::bind("127.0.0.1:8080").expect("failed to bind host socket");
-    socket.set_nonblocking(true).unwrap();
 
     let ss = Rc::new(socket);
 
@@ -35,45 +40,33 @@ fn main() {
     // kcp.ikcp_nodelay(true, 1, 10, true);
 
     loop {
-        let current = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u32;
+        let current = now();
 
-        kcp.ikcp_update(current);
-
-        loop {
-            let mut ss_buf = [0; 100];
-            match ss.recv_from(&mut ss_buf) {
-                Ok((a, _b)) => {
-                    if a > 0 {
-                        kcp.ikcp_input(&ss_buf[..a]).unwrap();
-                    }
-                }
-                Err(_x) => {
-                    break;
-                }
+        if kcp.ikcp_check(current) == current {
+            if let Err(e) = kcp.ikcp_update(current) {
+                println!("update error-->{}", e);
             }
         }
 
-        loop {
-            let mut read_buf = [0; 100];
-            match kcp.ikcp_recv(&mut read_buf) {
-                Ok(x) => {
-                    if x > 0 {
-                        println!(
-                            "recive-->{:?}",
-                            String::from_utf8(read_buf[..x].to_vec()).unwrap()
-                        );
-                    }
-                    kcp.ikcp_send(b"hello world").unwrap();
-                }
-                Err(_x) => {
-                    break;
+        // 阻塞到下一次需要 update 的时刻，期间 socket 可读则提前醒来
+        let wait = kcp.ikcp_check(current).wrapping_sub(current).max(1);
+        ss.set_read_timeout(Some(Duration::from_millis(wait as u64)))
+            .unwrap();
+
+        let mut ss_buf = [0; 1500];
+        match ss.recv_from(&mut ss_buf) {
+            Ok((a, _b)) => {
+                if a > 0 {
+                    kcp.ikcp_input(&ss_buf[..a]).unwrap();
                 }
             }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => panic!("recv error-->{}", e),
         }
 
-        sleep(Duration::from_secs(2));
+        while let Ok(message) = kcp.recv_message() {
+            println!("recive-->{:?}", String::from_utf8(message).unwrap());
+            kcp.ikcp_send(b"hello world").unwrap();
+        }
     }
 }
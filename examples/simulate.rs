@@ -0,0 +1,62 @@
+// 对应 C 版 test.cpp：在往返 10% 丢包（单程 5%）、rtt 60~120ms 的模拟链路上比较各个模式，
+// a 每 20ms 发送一个带时间戳的消息，b 原样返回，统计 rtt
+use kcp_rs::{Kcp, LinkConfig, Simulator};
+
+fn run(name: &str, nodelay: bool, interval: u32, resend: u32, nc: bool) {
+    let mut a = Kcp::ickp_create((), 0x11223344);
+    let mut b = Kcp::ickp_create((), 0x11223344);
+    for kcp in [&mut a, &mut b] {
+        kcp.ikcp_wndsize(128, 128);
+        kcp.ikcp_nodelay(nodelay, interval, resend, nc);
+    }
+    let link = LinkConfig::default().loss(0.05).delay(30).jitter(30);
+    let mut sim = Simulator::new(a, b, link, 0x1234);
+
+    let count = 1000;
+    let mut next_send = 0;
+    let mut sent = 0u32;
+    let mut received = 0u32;
+    let mut total_rtt = 0u64;
+    let mut max_rtt = 0;
+    let mut buf = [0; 8];
+    while received < count {
+        let now = sim.now();
+        if sent < count && now >= next_send {
+            let mut msg = [0; 8];
+            msg[..4].copy_from_slice(&sent.to_le_bytes());
+            msg[4..].copy_from_slice(&now.to_le_bytes());
+            sim.a_mut().ikcp_send(&msg).unwrap();
+            sent += 1;
+            next_send = now + 20;
+        }
+        while let Ok(n) = sim.b_mut().ikcp_recv(&mut buf) {
+            sim.b_mut().ikcp_send(&buf[..n]).unwrap();
+        }
+        while let Ok(n) = sim.a_mut().ikcp_recv(&mut buf) {
+            assert_eq!(n, 8);
+            let ts = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+            let rtt = now - ts;
+            total_rtt += rtt as u64;
+            max_rtt = max_rtt.max(rtt);
+            received += 1;
+        }
+        sim.advance(1);
+    }
+
+    let a = sim.a().stats();
+    println!(
+        "{} mode result ({}ms): avgrtt={} maxrtt={} tx={} retrans={}",
+        name,
+        sim.now(),
+        total_rtt / count as u64,
+        max_rtt,
+        sim.a_to_b().stats().sent_pkts + sim.b_to_a().stats().sent_pkts,
+        a.lost_segs + a.fast_retrans_segs,
+    );
+}
+
+fn main() {
+    run("default", false, 10, 0, false);
+    run("normal", false, 10, 0, true);
+    run("fast", true, 10, 2, true);
+}
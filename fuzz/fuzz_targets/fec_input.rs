@@ -0,0 +1,23 @@
+// 任意字节经过 FecDecoder 交给 Kcp，前两个字节决定数据分片和校验分片的个数
+#![no_main]
+
+mod frames;
+
+use frames::frames;
+use kcp_rs::{FecDecoder, Kcp};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let [data_shards, parity_shards, rest @ ..] = data else {
+        return;
+    };
+    let data_shards = (data_shards % 16 + 1) as usize;
+    let parity_shards = (parity_shards % 16 + 1) as usize;
+    let mut decoder = FecDecoder::new(data_shards, parity_shards).unwrap();
+    let mut kcp = Kcp::ickp_create((), 1);
+
+    for pkt in frames(rest) {
+        let _ = decoder.input(&mut kcp, pkt);
+        kcp.check_invariants();
+    }
+});
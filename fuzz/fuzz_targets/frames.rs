@@ -0,0 +1,14 @@
+// 把输入按长度前缀切分成多个报文：每个报文前面是 2 字节小端序的长度，
+// 最后一个报文长度不足时取剩下的全部字节，报文中可以出现任意字节
+pub fn frames(mut data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        if data.len() < 2 {
+            return None;
+        }
+        let len = u16::from_le_bytes([data[0], data[1]]) as usize;
+        let rest = &data[2..];
+        let (pkt, tail) = rest.split_at(len.min(rest.len()));
+        data = tail;
+        Some(pkt)
+    })
+}
@@ -0,0 +1,36 @@
+// 任意字节按长度前缀切分成一个或多个报文交给 Segments 和 ikcp_input，conv 固定为 1
+#![no_main]
+
+mod frames;
+
+use frames::frames;
+use kcp_rs::{Kcp, Segments};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut kcp = Kcp::ickp_create((), 1);
+    kcp.ikcp_nodelay(true, 10, 2, false);
+    let mut buf = vec![0; 64 * 1024];
+
+    // 每个报文前补上正确的 conv 以便进入后面的解析
+    for (i, pkt) in frames(data).enumerate() {
+        let mut datagram = 1u32.to_le_bytes().to_vec();
+        datagram.extend_from_slice(pkt);
+
+        // 解析成功的 segment 重新编码后与原始字节一致
+        let mut segments = Segments::new(&datagram);
+        let mut encoded = Vec::new();
+        for seg in segments.by_ref().flatten() {
+            seg.encode(&mut encoded);
+        }
+        assert_eq!(encoded, &datagram[..segments.offset()]);
+
+        let _ = kcp.ikcp_input(&datagram);
+        kcp.check_invariants();
+
+        kcp.handle_timeout(i as u32 * 10);
+        while kcp.poll_transmit().is_some() {}
+        while kcp.ikcp_recv(&mut buf).is_ok() {}
+        kcp.check_invariants();
+    }
+});
@@ -0,0 +1,109 @@
+// 任意的报文与 send/recv/update 调用交错执行，每一步之后检查不变量
+#![no_main]
+
+use arbitrary::Arbitrary;
+use kcp_rs::Kcp;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    // 原始字节
+    Input(Vec<u8>),
+
+    // 头部合法的 segment，sn 和 una 为相对窗口起点的偏移，便于落在窗口附近
+    Segment {
+        cmd: u8,
+        frg: u8,
+        wnd: u16,
+        ts: i32,
+        sn: i16,
+        una: i16,
+        data: Vec<u8>,
+    },
+
+    Send(u16),
+    Recv(u16),
+    Update(u16),
+
+    // 把待发送的报文交回给自己
+    Loopback,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    nodelay: bool,
+    resend: u8,
+    nocwnd: bool,
+    stream: bool,
+    start: u32,
+    ops: Vec<Op>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut kcp = if input.stream {
+        Kcp::ickp_create_stream((), 1)
+    } else {
+        Kcp::ickp_create((), 1)
+    };
+    kcp.ikcp_nodelay(input.nodelay, 10, input.resend as u32 % 4, input.nocwnd);
+    let mut now = input.start;
+    let payload = vec![0x5a; u16::MAX as usize];
+    let mut buf = vec![0; u16::MAX as usize];
+
+    for op in input.ops {
+        match op {
+            Op::Input(pkt) => {
+                let _ = kcp.ikcp_input(&pkt);
+            }
+            Op::Segment {
+                cmd,
+                frg,
+                wnd,
+                ts,
+                sn,
+                una,
+                data,
+            } => {
+                let state = kcp.snapshot();
+                let cmd = 81 + cmd % 4;
+                let base = if cmd == 82 {
+                    state.snd_una
+                } else {
+                    state.rcv_nxt
+                };
+                let mut pkt = Vec::with_capacity(24 + data.len());
+                pkt.extend_from_slice(&1u32.to_le_bytes());
+                pkt.push(cmd);
+                pkt.push(frg);
+                pkt.extend_from_slice(&wnd.to_le_bytes());
+                pkt.extend_from_slice(&now.wrapping_add(ts as u32).to_le_bytes());
+                pkt.extend_from_slice(&base.wrapping_add(sn as u32).to_le_bytes());
+                pkt.extend_from_slice(&state.snd_una.wrapping_add(una as u32).to_le_bytes());
+                pkt.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                pkt.extend_from_slice(&data);
+                let _ = kcp.ikcp_input(&pkt);
+            }
+            Op::Send(n) => {
+                // 限制待发送的数据量，只检查协议本身占用的内存
+                if kcp.ikcp_waitsnd() < 1024 {
+                    let _ = kcp.ikcp_send(&payload[..n as usize]);
+                }
+            }
+            Op::Recv(n) => {
+                let _ = kcp.ikcp_peeksize();
+                let _ = kcp.ikcp_recv(&mut buf[..n as usize]);
+            }
+            Op::Update(elapsed) => {
+                now = now.wrapping_add(elapsed as u32);
+                kcp.handle_timeout(now);
+            }
+            Op::Loopback => {
+                let pkts: Vec<_> = std::iter::from_fn(|| kcp.poll_transmit()).collect();
+                for pkt in pkts {
+                    let _ = kcp.ikcp_input(&pkt);
+                }
+            }
+        }
+        kcp.check_invariants();
+    }
+});
@@ -0,0 +1,381 @@
+use crate::congestion::{AckEvent, CongestionController};
+use crate::kcp::diff;
+use std::collections::VecDeque;
+
+// startup 阶段的增益 2/ln2，每一轮发送速率翻倍
+const STARTUP_GAIN: f64 = 2.885;
+const DRAIN_GAIN: f64 = 1.0 / STARTUP_GAIN;
+const CWND_GAIN: f64 = 2.0;
+
+// probe_bw 阶段循环使用的 pacing 增益，每个 min rtt 切换一次
+const PACING_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+// 带宽估计取最近 10 轮的最大值
+const BW_WINDOW_ROUNDS: u32 = 10;
+
+// 连续 3 轮带宽增长不足 25% 认为已经占满瓶颈带宽
+const FULL_BW_THRESH: f64 = 1.25;
+const FULL_BW_ROUNDS: u32 = 3;
+
+// min rtt 超过 10s 没有更新时进入 probe_rtt，把窗口降到 4 个 segment 维持至少 200ms
+const MIN_RTT_WINDOW: u32 = 10000;
+const PROBE_RTT_TIME: u32 = 200;
+
+const MIN_CWND: u32 = 4;
+const INIT_CWND: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+// 基于瓶颈带宽和最小 rtt 的拥塞控制，参考 BBR v1
+//
+// 带宽由 ack 的到达速率估计，rtt 取 ikcp_update_ack 中的样本，拥塞窗口为 2 倍 BDP，
+// 并通过 pacing_rate() 限制每次 flush 新发送的数据量。丢包不会减小窗口，
+// 适合随机丢包较多的链路。
+#[derive(Debug, Clone)]
+pub struct BbrCongestion {
+    mode: Mode,
+    cwnd: u32,
+
+    // 瓶颈带宽估计，字节/秒
+    btl_bw: u64,
+    // (轮次, 该轮最大的带宽样本)
+    bw_samples: VecDeque<(u32, u64)>,
+
+    // 最小 rtt 及其更新时间，0 表示还没有样本
+    min_rtt: u32,
+    min_rtt_ts: u32,
+
+    // 当前带宽样本的起始时间和已确认的字节数
+    sample_start: Option<u32>,
+    sample_bytes: u64,
+
+    // 以 min rtt 为长度的轮次
+    round: u32,
+    round_start: Option<u32>,
+
+    full_bw: u64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+
+    cycle_index: usize,
+    probe_rtt_done: u32,
+    prior_cwnd: u32,
+}
+
+impl Default for BbrCongestion {
+    fn default() -> Self {
+        BbrCongestion {
+            mode: Mode::Startup,
+            cwnd: INIT_CWND,
+            btl_bw: 0,
+            bw_samples: VecDeque::new(),
+            min_rtt: 0,
+            min_rtt_ts: 0,
+            sample_start: None,
+            sample_bytes: 0,
+            round: 0,
+            round_start: None,
+            full_bw: 0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            probe_rtt_done: 0,
+            prior_cwnd: 0,
+        }
+    }
+}
+
+impl BbrCongestion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 当前的瓶颈带宽估计，字节/秒
+    pub fn bandwidth(&self) -> u64 {
+        self.btl_bw
+    }
+
+    // 当前的最小 rtt 估计，没有样本时为 0
+    pub fn min_rtt(&self) -> u32 {
+        self.min_rtt
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => STARTUP_GAIN,
+            Mode::Drain => DRAIN_GAIN,
+            Mode::ProbeBw => PACING_GAINS[self.cycle_index],
+            Mode::ProbeRtt => 1.0,
+        }
+    }
+
+    // 带宽时延积，以 segment 为单位
+    fn bdp(&self, mss: u32) -> u32 {
+        let bytes = self.btl_bw * self.min_rtt as u64 / 1000;
+        bytes.div_ceil(mss.max(1) as u64) as u32
+    }
+
+    fn update_min_rtt(&mut self, now: u32, rtt: u32) {
+        let expired = diff(now, self.min_rtt_ts) > MIN_RTT_WINDOW as i32;
+        if self.min_rtt == 0 || rtt <= self.min_rtt || (expired && self.mode != Mode::ProbeRtt) {
+            if expired && rtt > self.min_rtt && self.min_rtt != 0 {
+                // min rtt 过期，先降低窗口排空队列再重新测量
+                self.mode = Mode::ProbeRtt;
+                self.prior_cwnd = self.cwnd;
+                self.probe_rtt_done = now.wrapping_add(PROBE_RTT_TIME.max(self.min_rtt));
+            }
+            self.min_rtt = rtt.max(1);
+            self.min_rtt_ts = now;
+        }
+    }
+
+    fn update_bandwidth(&mut self, ack: &AckEvent) {
+        let start = match self.sample_start {
+            Some(start) => start,
+            None => {
+                // 第一批确认的数据是在采样开始前发送的，不计入
+                self.sample_start = Some(ack.current);
+                return;
+            }
+        };
+        self.sample_bytes += ack.acked_bytes;
+        let elapsed = diff(ack.current, start);
+        if elapsed <= 0 || (elapsed as u32) < self.min_rtt.max(1) {
+            return;
+        }
+        let bw = self.sample_bytes * 1000 / elapsed as u64;
+        self.sample_start = Some(ack.current);
+        self.sample_bytes = 0;
+
+        match self.bw_samples.back_mut() {
+            Some((round, max)) if *round == self.round => *max = (*max).max(bw),
+            _ => self.bw_samples.push_back((self.round, bw)),
+        }
+        while let Some(&(round, _)) = self.bw_samples.front() {
+            if self.round.wrapping_sub(round) >= BW_WINDOW_ROUNDS {
+                self.bw_samples.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.btl_bw = self.bw_samples.iter().map(|&(_, bw)| bw).max().unwrap_or(0);
+    }
+
+    // 每经过一个 min rtt 开始新的一轮
+    fn update_round(&mut self, now: u32, inflight: u32, mss: u32) {
+        // 第一轮从第一个 ack 开始计算，时钟可以从任意值开始
+        let start = *self.round_start.get_or_insert(now);
+        if self.min_rtt == 0 || diff(now, start) < self.min_rtt as i32 {
+            return;
+        }
+        self.round = self.round.wrapping_add(1);
+        self.round_start = Some(now);
+
+        if !self.filled_pipe {
+            if self.btl_bw as f64 >= self.full_bw as f64 * FULL_BW_THRESH {
+                self.full_bw = self.btl_bw;
+                self.full_bw_rounds = 0;
+            } else {
+                self.full_bw_rounds += 1;
+                if self.full_bw_rounds >= FULL_BW_ROUNDS {
+                    self.filled_pipe = true;
+                }
+            }
+        }
+
+        match self.mode {
+            Mode::Startup if self.filled_pipe => self.mode = Mode::Drain,
+            Mode::ProbeBw => self.cycle_index = (self.cycle_index + 1) % PACING_GAINS.len(),
+            _ => {}
+        }
+        if self.mode == Mode::Drain && inflight <= self.bdp(mss) {
+            self.mode = Mode::ProbeBw;
+            self.cycle_index = 0;
+        }
+    }
+
+    fn update_cwnd(&mut self, ack: &AckEvent) {
+        if self.mode == Mode::ProbeRtt {
+            if diff(ack.current, self.probe_rtt_done) >= 0 {
+                self.mode = if self.filled_pipe {
+                    Mode::ProbeBw
+                } else {
+                    Mode::Startup
+                };
+                self.min_rtt_ts = ack.current;
+                self.cwnd = self.cwnd.max(self.prior_cwnd);
+            }
+            return;
+        }
+
+        let target = ((self.bdp(ack.mss) as f64 * CWND_GAIN) as u32).max(MIN_CWND);
+        if self.filled_pipe {
+            self.cwnd = (self.cwnd + ack.acked_segs).min(target);
+        } else if self.cwnd < target || self.btl_bw == 0 {
+            self.cwnd += ack.acked_segs;
+        }
+        self.cwnd = self.cwnd.clamp(MIN_CWND, ack.rmt_wnd.max(MIN_CWND));
+    }
+}
+
+impl CongestionController for BbrCongestion {
+    fn on_ack(&mut self, ack: &AckEvent) {
+        if let Some(rtt) = ack.rtt {
+            self.update_min_rtt(ack.current, rtt);
+        }
+        if ack.acked_segs > 0 {
+            self.update_bandwidth(ack);
+        }
+        self.update_round(ack.current, ack.inflight, ack.mss);
+        self.update_cwnd(ack);
+    }
+
+    // 丢包不作为拥塞信号
+    fn on_fast_retransmit(&mut self, _inflight: u32, _resent: u32, _mss: u32) {}
+
+    fn on_timeout_loss(&mut self, _cwnd: u32, _mss: u32) {}
+
+    fn window(&self) -> u32 {
+        match self.mode {
+            Mode::ProbeRtt => MIN_CWND,
+            _ => self.cwnd,
+        }
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        if self.btl_bw == 0 {
+            return None;
+        }
+        Some((self.btl_bw as f64 * self.pacing_gain()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KcpConfig;
+    use crate::congestion::CongestionMode;
+    use crate::kcp::Kcp;
+    use crate::sim::{LinkConfig, SimLink};
+
+    // 在有随机丢包的链路上单向传输 duration 毫秒，返回接收到的字节数
+    fn goodput(mode: CongestionMode, loss: u64, duration: u32) -> usize {
+        let config = KcpConfig::default()
+            .interval(10)
+            .fast_resend(2)
+            .window(256, 256)
+            .dead_link(1000)
+            .congestion(mode);
+        let mut a = Kcp::with_config((), 1, &config).unwrap();
+        let mut b = Kcp::with_config((), 1, &config).unwrap();
+        // 单向时延 30ms，瓶颈带宽 500KB/s，队列最多 100ms
+        let link = LinkConfig::default()
+            .loss(loss as f64 / 100.0)
+            .delay(30)
+            .bandwidth(500_000)
+            .queue_delay(100);
+        let mut forward = SimLink::new(link.clone(), 0x9e3779b97f4a7c15);
+        let mut backward = SimLink::new(link, 0xd1b54a32d192ed03);
+        let msg = [0u8; 1000];
+        let mut buf = [0u8; 2000];
+        let mut received = 0;
+
+        for now in 0..duration {
+            while a.ikcp_waitsnd() < 512 {
+                a.ikcp_send(&msg).unwrap();
+            }
+            a.handle_timeout(now);
+            b.handle_timeout(now);
+            while let Some(pkt) = a.poll_transmit() {
+                forward.send(now, pkt);
+            }
+            while let Some(pkt) = b.poll_transmit() {
+                backward.send(now, pkt);
+            }
+            while let Some(pkt) = forward.recv(now) {
+                b.ikcp_input(&pkt).unwrap();
+            }
+            while let Some(pkt) = backward.recv(now) {
+                a.ikcp_input(&pkt).unwrap();
+            }
+            while let Ok(n) = b.ikcp_recv(&mut buf) {
+                received += n;
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn estimates_bandwidth_and_rtt() {
+        let mut cc = BbrCongestion::new();
+        // 每 10ms 确认 5000 字节，rtt 60ms
+        for i in 0..200 {
+            cc.on_ack(&AckEvent {
+                current: i * 10,
+                una_advanced: true,
+                acked_segs: 5,
+                acked_bytes: 5000,
+                rtt: Some(60 + i % 3),
+                srtt: 61,
+                inflight: 30,
+                mss: 1000,
+                rmt_wnd: 256,
+            });
+        }
+        assert_eq!(cc.min_rtt(), 60);
+        assert!((450_000..=550_000).contains(&cc.bandwidth()));
+        assert_ne!(cc.mode, Mode::Startup);
+        // 2 倍 BDP = 2 * 500KB/s * 60ms / 1000
+        assert!((50..=70).contains(&cc.window()));
+        assert!(cc.pacing_rate().is_some());
+
+        // 丢包不会减小窗口
+        let wnd = cc.window();
+        cc.on_timeout_loss(wnd, 1000);
+        cc.on_fast_retransmit(wnd, 2, 1000);
+        assert_eq!(cc.window(), wnd);
+    }
+
+    #[test]
+    fn rounds_advance_with_large_clock() {
+        let mut cc = BbrCongestion::new();
+        // 整个过程中时钟都大于 2^31，不会回绕到 0 附近
+        let start = u32::MAX - 10_000;
+        for i in 0..200 {
+            cc.on_ack(&AckEvent {
+                current: start.wrapping_add(i * 10),
+                una_advanced: true,
+                acked_segs: 5,
+                acked_bytes: 5000,
+                rtt: Some(60),
+                srtt: 60,
+                inflight: 30,
+                mss: 1000,
+                rmt_wnd: 256,
+            });
+        }
+        assert!(cc.round > 10);
+        assert_ne!(cc.mode, Mode::Startup);
+    }
+
+    #[test]
+    fn outperforms_loss_based_under_random_loss() {
+        let loss = goodput(CongestionMode::Loss, 5, 5000);
+        let bbr = goodput(CongestionMode::Bbr, 5, 5000);
+        assert!(
+            bbr > loss * 2,
+            "loss based: {} bytes, bbr: {} bytes",
+            loss,
+            bbr
+        );
+
+        // 瓶颈带宽为 500KB/s，考虑头部开销和丢包后不应超过这个值
+        assert!(bbr < 500 * 5000, "bbr: {} bytes", bbr);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::error::KcpError;
+use crate::kcp::{Kcp, LinkState};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::cmp::min;
+
+// 每条 kcp 消息开头 1 字节的头部
+const CHUNK_LAST: u8 = 0; // 消息的最后一段
+const CHUNK_MORE: u8 = 1; // 后面还有同一条消息的数据
+
+// recv 默认最多拼接的消息大小
+const DEFAULT_RECV_LIMIT: usize = 16 << 20;
+
+// 大消息模式：在消息模式的 kcp 之上把任意大小的消息拆成多条不超过 max_message_size 的 kcp 消息，
+// 每条前面加上 1 字节的头部，0 表示消息结束，1 表示后面还有数据。
+// 报文仍然是普通的 kcp 报文，但两端都必须使用这一层。
+// 调用者仍然需要通过 get_mut() 定期调用 ikcp_update 并把收到的报文交给 ikcp_input。
+pub struct ChunkedKcp<W> {
+    kcp: Kcp<W>,
+
+    // recv 拼接的消息的最大长度，超过后返回 MessageTooLarge 并丢弃这条消息剩下的部分
+    recv_limit: usize,
+
+    // 已经收到但还不完整的消息
+    partial: BytesMut,
+
+    // 正在丢弃超过 recv_limit 的消息
+    discarding: bool,
+}
+
+impl<W> ChunkedKcp<W> {
+    // kcp 必须是消息模式，流模式下没有消息边界，无法区分每一段的头部
+    pub fn new(kcp: Kcp<W>) -> Result<Self, KcpError> {
+        if kcp.is_stream() {
+            return Err(KcpError::InvalidConfig(
+                "ChunkedKcp requires a message mode kcp",
+            ));
+        }
+        Ok(ChunkedKcp {
+            kcp,
+            recv_limit: DEFAULT_RECV_LIMIT,
+            partial: BytesMut::new(),
+            discarding: false,
+        })
+    }
+
+    pub fn recv_limit(mut self, limit: usize) -> Self {
+        self.recv_limit = limit;
+        self
+    }
+
+    pub fn get_ref(&self) -> &Kcp<W> {
+        &self.kcp
+    }
+
+    pub fn get_mut(&mut self) -> &mut Kcp<W> {
+        &mut self.kcp
+    }
+
+    pub fn into_inner(self) -> Kcp<W> {
+        self.kcp
+    }
+
+    // 发送任意大小的消息，返回消息的长度
+    pub fn send(&mut self, mut data: Bytes) -> Result<usize, KcpError> {
+        let n = data.len();
+        if n == 0 {
+            return Err(KcpError::EmptyPayload);
+        }
+
+        // 每段加上头部正好不超过 max_message_size，send_bytes 只会因为 DeadLink 在第一段失败，
+        // 不会在中途失败而让对端收到一条被截断的消息
+        let size = self.kcp.max_message_size() - 1;
+        while !data.is_empty() {
+            let chunk = data.split_to(min(size, data.len()));
+            let mut buf = BytesMut::with_capacity(chunk.len() + 1);
+            buf.put_u8(if data.is_empty() {
+                CHUNK_LAST
+            } else {
+                CHUNK_MORE
+            });
+            buf.extend_from_slice(&chunk);
+            self.kcp.send_bytes(buf.freeze())?;
+        }
+        Ok(n)
+    }
+
+    // 接收一条完整的消息，只有一段的消息直接返回，不复制数据
+    pub fn recv(&mut self) -> Result<Bytes, KcpError> {
+        loop {
+            let (chunk, last) = self.next_chunk()?;
+            if self.discarding {
+                self.discarding = !last;
+                continue;
+            }
+
+            if self.partial.len() + chunk.len() > self.recv_limit {
+                self.partial = BytesMut::new();
+                self.discarding = !last;
+                return Err(KcpError::MessageTooLarge {
+                    limit: self.recv_limit,
+                });
+            }
+            if last && self.partial.is_empty() {
+                return Ok(chunk);
+            }
+            self.partial.extend_from_slice(&chunk);
+            if last {
+                return Ok(self.partial.split().freeze());
+            }
+        }
+    }
+
+    // 流式接收：返回下一段数据以及它是否是消息的最后一段，不受 recv_limit 限制，
+    // 可以在消息全部到达之前开始处理。recv 已经拼接了一部分时先返回这一部分
+    pub fn recv_chunk(&mut self) -> Result<(Bytes, bool), KcpError> {
+        if !self.partial.is_empty() {
+            return Ok((self.partial.split().freeze(), false));
+        }
+        loop {
+            let (chunk, last) = self.next_chunk()?;
+            if self.discarding {
+                self.discarding = !last;
+                continue;
+            }
+            return Ok((chunk, last));
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<(Bytes, bool), KcpError> {
+        let Some(mut chunk) = self.kcp.recv_bytes() else {
+            return Err(if self.kcp.state() == LinkState::Dead {
+                KcpError::DeadLink
+            } else {
+                KcpError::Empty
+            });
+        };
+        let last = match chunk.first() {
+            Some(&CHUNK_LAST) => true,
+            Some(&CHUNK_MORE) => false,
+            _ => return Err(KcpError::InvalidChunk),
+        };
+        chunk.advance(1);
+        Ok((chunk, last))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KcpConfig;
+
+    fn pair() -> (ChunkedKcp<()>, ChunkedKcp<()>) {
+        let session = || {
+            let mut kcp = Kcp::ickp_create((), 1);
+            kcp.ikcp_nodelay(true, 10, 2, true);
+            kcp.ikcp_wndsize(512, 128);
+            ChunkedKcp::new(kcp).unwrap()
+        };
+        (session(), session())
+    }
+
+    // 在无损的链路上交换报文，每一轮用 recv 从 b 中读出数据，直到两端都没有待发送的数据
+    fn exchange(
+        a: &mut ChunkedKcp<()>,
+        b: &mut ChunkedKcp<()>,
+        now: &mut u32,
+        mut recv: impl FnMut(&mut ChunkedKcp<()>),
+    ) {
+        for _ in 0..1000 {
+            a.get_mut().handle_timeout(*now);
+            b.get_mut().handle_timeout(*now);
+            while let Some(pkt) = a.get_mut().poll_transmit() {
+                b.get_mut().input_bytes(pkt).unwrap();
+            }
+            while let Some(pkt) = b.get_mut().poll_transmit() {
+                a.get_mut().input_bytes(pkt).unwrap();
+            }
+            recv(b);
+            *now += 10;
+            if a.get_ref().ikcp_waitsnd() == 0 && b.get_ref().ikcp_waitsnd() == 0 {
+                return;
+            }
+        }
+        panic!("transfer did not finish");
+    }
+
+    // 读出 b 中所有的消息和错误
+    fn recv_all(b: &mut ChunkedKcp<()>, out: &mut Vec<Result<Bytes, KcpError>>) {
+        loop {
+            match b.recv() {
+                Err(KcpError::Empty) => return,
+                result => out.push(result),
+            }
+        }
+    }
+
+    #[test]
+    fn large_message_round_trip() {
+        let (mut a, mut b) = pair();
+        let mut now = 0;
+        let max = a.get_ref().max_message_size();
+        let large: Bytes = (0..max * 3 + 100).map(|i| (i % 251) as u8).collect();
+        a.send(large.clone()).unwrap();
+        a.send(Bytes::from_static(b"small")).unwrap();
+        assert!(matches!(a.send(Bytes::new()), Err(KcpError::EmptyPayload)));
+        // 拆成 4 条 kcp 消息，每条都不超过 max_message_size
+        assert_eq!(a.get_ref().snapshot().snd_queue, 3 * 127 + 1 + 1);
+
+        // 整条消息远大于接收窗口，边传输边接收
+        let mut received = Vec::new();
+        exchange(&mut a, &mut b, &mut now, |b| recv_all(b, &mut received));
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].as_ref().unwrap(), &large);
+        assert_eq!(&received[1].as_ref().unwrap()[..], b"small");
+    }
+
+    #[test]
+    fn streaming_and_limit() {
+        let (mut a, b) = pair();
+        let mut b = ChunkedKcp::new(b.into_inner()).unwrap().recv_limit(200_000);
+        let mut now = 0;
+        let max = a.get_ref().max_message_size();
+        let large: Bytes = (0..max * 2).map(|i| i as u8).collect();
+
+        // 超过 recv_limit 的消息被丢弃，后面的消息不受影响
+        a.send(large.clone()).unwrap();
+        a.send(Bytes::from_static(b"next")).unwrap();
+        let mut received = Vec::new();
+        exchange(&mut a, &mut b, &mut now, |b| recv_all(b, &mut received));
+        assert!(matches!(
+            received[..],
+            [Err(KcpError::MessageTooLarge { limit: 200_000 }), Ok(ref next)] if next == "next"
+        ));
+
+        // recv_chunk 不受 recv_limit 限制
+        a.send(large.clone()).unwrap();
+        let mut streamed = BytesMut::new();
+        let mut done = false;
+        exchange(&mut a, &mut b, &mut now, |b| {
+            while let Ok((chunk, last)) = b.recv_chunk() {
+                streamed.extend_from_slice(&chunk);
+                done |= last;
+            }
+        });
+        assert!(done);
+        assert_eq!(streamed, large);
+
+        // 对端直接使用 kcp 发送的消息没有合法的头部
+        a.get_mut().ikcp_send(&[7]).unwrap();
+        exchange(&mut a, &mut b, &mut now, |_| {});
+        assert!(matches!(b.recv(), Err(KcpError::InvalidChunk)));
+    }
+
+    #[test]
+    fn new_requires_message_mode() {
+        assert!(matches!(
+            ChunkedKcp::new(Kcp::ickp_create_stream((), 1)),
+            Err(KcpError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn failed_send_queues_nothing() {
+        let config = KcpConfig::default().interval(10).dead_link(1);
+        let mut a = ChunkedKcp::new(Kcp::with_config((), 1, &config).unwrap()).unwrap();
+        a.send(Bytes::from_static(b"lost")).unwrap();
+        let mut now = 0;
+        while a.get_ref().state() != LinkState::Dead {
+            a.get_mut().handle_timeout(now);
+            while a.get_mut().poll_transmit().is_some() {}
+            now += 10;
+        }
+
+        // 整条消息在放入任何一段之前就被拒绝
+        let queued = a.get_ref().snapshot().snd_queue;
+        let large: Bytes = vec![0; a.get_ref().max_message_size() * 2].into();
+        assert!(matches!(a.send(large), Err(KcpError::DeadLink)));
+        assert_eq!(a.get_ref().snapshot().snd_queue, queued);
+    }
+}
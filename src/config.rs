@@ -0,0 +1,186 @@
+use crate::congestion::CongestionMode;
+use crate::error::KcpError;
+use crate::kcp::{IKCP_DEADLINK, IKCP_INTERVAL, IKCP_MTU_DEF, IKCP_WND_RCV, IKCP_WND_SND};
+
+const IKCP_MTU_MIN: u32 = 50;
+const IKCP_MTU_MAX: u32 = 65535;
+const IKCP_WND_MAX: u32 = 65535; // wnd 字段只有 16 位
+
+// kcp 的全部可调参数，在创建 Kcp 时一次性校验，不会像 ikcp_* 设置函数那样静默修正
+//
+// let config = KcpConfig::fast().mtu(1200).window(256, 256);
+// let kcp = Kcp::with_config(output, conv, &config)?;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KcpConfig {
+    pub(crate) mtu: u32,
+    pub(crate) snd_wnd: u32,
+    pub(crate) rcv_wnd: u32,
+    pub(crate) nodelay: bool,
+    pub(crate) interval: u32,
+    pub(crate) fast_resend: u32,
+    pub(crate) no_cwnd: bool,
+    pub(crate) stream: bool,
+    pub(crate) dead_link: u32,
+    pub(crate) fastack_conserve: bool,
+    pub(crate) congestion: CongestionMode,
+}
+
+impl Default for KcpConfig {
+    // 协议默认模式：ikcp_nodelay(kcp, 0, 100, 0, 0)
+    fn default() -> Self {
+        KcpConfig {
+            mtu: IKCP_MTU_DEF,
+            snd_wnd: IKCP_WND_SND,
+            rcv_wnd: IKCP_WND_RCV,
+            nodelay: false,
+            interval: IKCP_INTERVAL,
+            fast_resend: 0,
+            no_cwnd: false,
+            stream: false,
+            dead_link: IKCP_DEADLINK,
+            fastack_conserve: false,
+            congestion: CongestionMode::Loss,
+        }
+    }
+}
+
+impl KcpConfig {
+    // 普通模式：ikcp_nodelay(kcp, 0, 40, 0, 0)
+    pub fn normal() -> Self {
+        KcpConfig::default().interval(40)
+    }
+
+    // 快速模式（kcptun 的 fast）：ikcp_nodelay(kcp, 0, 30, 2, 1)
+    pub fn fast() -> Self {
+        KcpConfig::default()
+            .interval(30)
+            .fast_resend(2)
+            .no_cwnd(true)
+    }
+
+    // 极速模式：ikcp_nodelay(kcp, 1, 10, 2, 1) 且 ikcp_wndsize(kcp, 128, 128)
+    pub fn fastest() -> Self {
+        KcpConfig::default()
+            .nodelay(true)
+            .interval(10)
+            .fast_resend(2)
+            .no_cwnd(true)
+            .window(128, 128)
+    }
+
+    // 最大传输单元，需要在 50 到 65535 之间
+    pub fn mtu(mut self, mtu: u32) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    // 发送窗口和接收窗口，接收窗口不能小于 128
+    pub fn window(mut self, snd_wnd: u32, rcv_wnd: u32) -> Self {
+        self.snd_wnd = snd_wnd;
+        self.rcv_wnd = rcv_wnd;
+        self
+    }
+
+    // 无延迟模式，最小 rto 降为 30ms，重传时 rto 只增加一半
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    // 内部 flush 间隔，需要在 10 到 5000 毫秒之间
+    pub fn interval(mut self, interval: u32) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    // 触发快速重传的重复 ack 个数，0 表示关闭
+    pub fn fast_resend(mut self, resend: u32) -> Self {
+        self.fast_resend = resend;
+        self
+    }
+
+    // 关闭拥塞控制
+    pub fn no_cwnd(mut self, no_cwnd: bool) -> Self {
+        self.no_cwnd = no_cwnd;
+        self
+    }
+
+    // 流模式
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    // 单个 segment 的最大发送次数，超过后连接被认为已断开
+    pub fn dead_link(mut self, dead_link: u32) -> Self {
+        self.dead_link = dead_link;
+        self
+    }
+
+    // 与 C 版编译时定义 IKCP_FASTACK_CONSERVE 的行为一致，见 Kcp::set_fastack_conserve
+    pub fn fastack_conserve(mut self, conserve: bool) -> Self {
+        self.fastack_conserve = conserve;
+        self
+    }
+
+    // 拥塞控制算法，no_cwnd 为 true 时不生效
+    pub fn congestion(mut self, congestion: CongestionMode) -> Self {
+        self.congestion = congestion;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), KcpError> {
+        if !(IKCP_MTU_MIN..=IKCP_MTU_MAX).contains(&self.mtu) {
+            return Err(KcpError::InvalidMtu(self.mtu));
+        }
+        if !(10..=5000).contains(&self.interval) {
+            return Err(KcpError::InvalidConfig(
+                "interval must be between 10 and 5000 ms",
+            ));
+        }
+        if self.snd_wnd == 0 || self.snd_wnd > IKCP_WND_MAX {
+            return Err(KcpError::InvalidConfig(
+                "send window must be between 1 and 65535",
+            ));
+        }
+        if !(IKCP_WND_RCV..=IKCP_WND_MAX).contains(&self.rcv_wnd) {
+            return Err(KcpError::InvalidConfig(
+                "receive window must be between 128 and 65535",
+            ));
+        }
+        if self.dead_link == 0 {
+            return Err(KcpError::InvalidConfig("dead link must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_valid() {
+        for config in [
+            KcpConfig::default(),
+            KcpConfig::normal(),
+            KcpConfig::fast(),
+            KcpConfig::fastest(),
+        ] {
+            config.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(matches!(
+            KcpConfig::default().mtu(20).validate(),
+            Err(KcpError::InvalidMtu(20))
+        ));
+        assert!(KcpConfig::default().interval(1).validate().is_err());
+        assert!(KcpConfig::default().window(0, 128).validate().is_err());
+        assert!(KcpConfig::default().window(32, 64).validate().is_err());
+        assert!(KcpConfig::default().window(32, 70000).validate().is_err());
+        assert!(KcpConfig::default().dead_link(0).validate().is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::bbr::BbrCongestion;
+use crate::ledbat::LedbatCongestion;
+use std::fmt;
+
+const IKCP_THRESH_INIT: u32 = 2;
+const IKCP_THRESH_MIN: u32 = 2;
+
+// 一次 ikcp_input 中收到的确认信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckEvent {
+    // 收到确认时的时间戳
+    pub current: u32,
+
+    // snd_una 是否前移
+    pub una_advanced: bool,
+
+    // 本次从 snd_buf 中删除（被 una 或者 ack 确认）的 segment 数和负载字节数
+    pub acked_segs: u32,
+    pub acked_bytes: u64,
+
+    // 本次收到的最后一个 rtt 样本，以及更新后的平滑 rtt
+    pub rtt: Option<u32>,
+    pub srtt: u32,
+
+    // 确认之后仍在 snd_buf 中的 segment 数
+    pub inflight: u32,
+
+    pub mss: u32,
+
+    // 远端接收窗口，拥塞窗口不应超过这个值
+    pub rmt_wnd: u32,
+}
+
+// 拥塞控制算法，Kcp 在收到确认、快速重传和超时重传时调用对应的接口，
+// flush 时用 window() 限制 snd_buf 中的 segment 数
+//
+// 设置了 nocwnd 时 window() 不会生效，但其余的回调仍然会被调用
+pub trait CongestionController: Send {
+    fn on_ack(&mut self, ack: &AckEvent);
+
+    // inflight 为 snd_nxt - snd_una，resent 为快速重传的阈值
+    fn on_fast_retransmit(&mut self, inflight: u32, resent: u32, mss: u32);
+
+    // cwnd 为本次 flush 实际使用的发送窗口
+    fn on_timeout_loss(&mut self, cwnd: u32, mss: u32);
+
+    // 当前的拥塞窗口，以 segment 为单位
+    fn window(&self) -> u32;
+
+    // 慢启动阈值，没有这个概念的算法返回 0
+    fn ssthresh(&self) -> u32 {
+        0
+    }
+
+    // 每次 flush 结束时调用
+    fn on_flush(&mut self, _mss: u32) {}
+
+    // 发送速率，字节/秒。返回 Some 时每次 flush 新发送的数据量受这个速率限制
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+// KcpConfig 中可以选择的拥塞控制算法
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CongestionMode {
+    // kcp 原有的基于丢包的算法
+    #[default]
+    Loss,
+    // 基于带宽和 rtt 估计的 BbrCongestion
+    Bbr,
+    // 基于排队时延的低优先级 LedbatCongestion
+    Ledbat,
+}
+
+impl CongestionMode {
+    pub(crate) fn controller(self) -> Box<dyn CongestionController> {
+        match self {
+            CongestionMode::Loss => Box::new(KcpCongestion::new()),
+            CongestionMode::Bbr => Box::new(BbrCongestion::new()),
+            CongestionMode::Ledbat => Box::new(LedbatCongestion::new()),
+        }
+    }
+}
+
+impl fmt::Debug for dyn CongestionController {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CongestionController")
+            .field("window", &self.window())
+            .field("ssthresh", &self.ssthresh())
+            .finish()
+    }
+}
+
+// kcp 原有的拥塞控制：慢启动 + 拥塞避免，快速重传时窗口减半，超时重传时窗口降为 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KcpCongestion {
+    cwnd: u32,
+    ssthresh: u32,
+
+    //可发送的最大数据量
+    incr: u32,
+}
+
+impl Default for KcpCongestion {
+    fn default() -> Self {
+        KcpCongestion {
+            cwnd: 0,
+            ssthresh: IKCP_THRESH_INIT,
+            incr: 0,
+        }
+    }
+}
+
+impl KcpCongestion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CongestionController for KcpCongestion {
+    fn on_ack(&mut self, ack: &AckEvent) {
+        //根据接收到报文的 una 和 KCP 控制块的 una 参数进行流控
+        if !ack.una_advanced || self.cwnd >= ack.rmt_wnd {
+            return;
+        }
+        let mss = ack.mss;
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1;
+            self.incr += mss;
+        } else {
+            if self.incr < mss {
+                self.incr = mss;
+            }
+            self.incr += (mss * mss) / self.incr + (mss / 16);
+            if (self.cwnd + 1) * mss <= self.incr {
+                self.cwnd = self.incr.div_ceil(mss.max(1));
+            }
+        }
+        if self.cwnd > ack.rmt_wnd {
+            self.cwnd = ack.rmt_wnd;
+            self.incr = ack.rmt_wnd * mss;
+        }
+    }
+
+    fn on_fast_retransmit(&mut self, inflight: u32, resent: u32, mss: u32) {
+        self.ssthresh = (inflight / 2).max(IKCP_THRESH_MIN);
+        self.cwnd = self.ssthresh.saturating_add(resent);
+        self.incr = self.cwnd.saturating_mul(mss);
+    }
+
+    fn on_timeout_loss(&mut self, cwnd: u32, mss: u32) {
+        self.ssthresh = (cwnd / 2).max(IKCP_THRESH_MIN);
+        self.cwnd = 1;
+        self.incr = mss;
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    fn on_flush(&mut self, mss: u32) {
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(una_advanced: bool, rmt_wnd: u32) -> AckEvent {
+        AckEvent {
+            current: 0,
+            una_advanced,
+            acked_segs: 1,
+            acked_bytes: 100,
+            rtt: Some(10),
+            srtt: 10,
+            inflight: 0,
+            mss: 100,
+            rmt_wnd,
+        }
+    }
+
+    #[test]
+    fn slow_start_then_avoidance() {
+        let mut cc = KcpCongestion::new();
+        cc.on_flush(100);
+        assert_eq!(cc.window(), 1);
+
+        // ssthresh 为 2，慢启动阶段每个 ack 加 1
+        cc.on_ack(&ack(true, 128));
+        assert_eq!(cc.window(), 2);
+
+        // 拥塞避免阶段增长变慢
+        cc.on_ack(&ack(true, 128));
+        assert_eq!(cc.window(), 2);
+
+        // una 没有前移时不增长
+        for _ in 0..10 {
+            cc.on_ack(&ack(false, 128));
+        }
+        assert_eq!(cc.window(), 2);
+
+        // 不超过远端窗口
+        for _ in 0..100 {
+            cc.on_ack(&ack(true, 4));
+        }
+        assert_eq!(cc.window(), 4);
+    }
+
+    #[test]
+    fn losses_shrink_window() {
+        let mut cc = KcpCongestion::new();
+        cc.on_fast_retransmit(20, 2, 100);
+        assert_eq!((cc.window(), cc.ssthresh()), (12, 10));
+
+        cc.on_timeout_loss(12, 100);
+        assert_eq!((cc.window(), cc.ssthresh()), (1, 6));
+
+        cc.on_timeout_loss(1, 100);
+        assert_eq!((cc.window(), cc.ssthresh()), (1, 2));
+    }
+}
@@ -0,0 +1,637 @@
+use crate::error::KcpError;
+use crate::kcp::Kcp;
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes192, Aes256};
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use std::io::{self, Write};
+
+// AEAD 报文：salt(8) + 计数器(8) + 密文 + tag(16)
+const AEAD_SALT_SIZE: usize = 8;
+const AEAD_COUNTER_SIZE: usize = 8;
+const AEAD_TAG_SIZE: usize = 16;
+
+// 重放窗口的大小，落后最大计数器超过这个值的报文被丢弃
+const REPLAY_WINDOW: u64 = 1024;
+
+// kcp-go 的加密头部：nonce(16) + crc32(4)，整个报文使用 CFB 加密
+const BLOCK_NONCE_SIZE: usize = 16;
+const BLOCK_CRC_SIZE: usize = 4;
+
+// kcp-go 中 CFB 模式使用的固定 IV
+const INITIAL_VECTOR: [u8; 16] = [
+    167, 115, 79, 156, 18, 172, 27, 1, 164, 21, 242, 193, 252, 120, 230, 107,
+];
+
+// 对单个报文加解密，kcp 的 mtu 需要减去 overhead()
+pub trait PacketCrypt {
+    // 加密后报文增加的长度
+    fn overhead(&self) -> usize;
+
+    fn encrypt(&mut self, pkt: &[u8]) -> Vec<u8>;
+
+    // 校验失败返回 KcpError::Decrypt
+    fn decrypt(&mut self, pkt: &[u8]) -> Result<Vec<u8>, KcpError>;
+
+    // 解密报文并交给 input_bytes
+    fn input<W>(&mut self, kcp: &mut Kcp<W>, pkt: &[u8]) -> Result<usize, KcpError>
+    where
+        Self: Sized,
+    {
+        let pkt = self.decrypt(pkt)?;
+        kcp.input_bytes(pkt.into())
+    }
+}
+
+// 会话中的哪一端，两个方向使用不同的密钥，对端发出的报文不能被反射回来
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AeadRole {
+    Client,
+    Server,
+}
+
+impl AeadRole {
+    fn label(self) -> &'static [u8] {
+        match self {
+            AeadRole::Client => b"kcp-rs aead client to server",
+            AeadRole::Server => b"kcp-rs aead server to client",
+        }
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            AeadRole::Client => AeadRole::Server,
+            AeadRole::Server => AeadRole::Client,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum AeadAlgorithm {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+enum AeadCipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl AeadCipher {
+    // 由预共享密钥、方向和发送方的 salt 派生出这个方向的密钥
+    fn derive(hkdf: &Hkdf<Sha256>, algorithm: AeadAlgorithm, role: AeadRole, salt: &[u8]) -> Self {
+        let info = [role.label(), salt].concat();
+        let mut okm = [0u8; 32];
+        let len = match algorithm {
+            AeadAlgorithm::Aes128Gcm => 16,
+            _ => 32,
+        };
+        hkdf.expand(&info, &mut okm[..len])
+            .expect("hkdf output too long");
+        match algorithm {
+            AeadAlgorithm::Aes128Gcm => AeadCipher::Aes128Gcm(Box::new(Aes128Gcm::new(
+                GenericArray::from_slice(&okm[..16]),
+            ))),
+            AeadAlgorithm::Aes256Gcm => {
+                AeadCipher::Aes256Gcm(Box::new(Aes256Gcm::new(GenericArray::from_slice(&okm))))
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => AeadCipher::ChaCha20Poly1305(Box::new(
+                ChaCha20Poly1305::new(GenericArray::from_slice(&okm)),
+            )),
+        }
+    }
+}
+
+// 按计数器记录已经收到的报文，与 IPsec/WireGuard 的滑动窗口相同
+struct ReplayWindow {
+    max: u64,
+    bits: [u64; (REPLAY_WINDOW / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow {
+            max: 0,
+            bits: [0; (REPLAY_WINDOW / 64) as usize],
+        }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let i = counter % REPLAY_WINDOW;
+        ((i / 64) as usize, 1 << (i % 64))
+    }
+
+    // 计数器从 1 开始，0、重复的和太旧的计数器都不能接受
+    fn check(&self, counter: u64) -> bool {
+        if counter == 0 {
+            return false;
+        }
+        if counter > self.max {
+            return true;
+        }
+        if self.max - counter >= REPLAY_WINDOW {
+            return false;
+        }
+        let (word, mask) = Self::bit(counter);
+        self.bits[word] & mask == 0
+    }
+
+    // 只在报文通过认证之后更新
+    fn update(&mut self, counter: u64) {
+        if counter > self.max {
+            if counter - self.max >= REPLAY_WINDOW {
+                self.bits = [0; (REPLAY_WINDOW / 64) as usize];
+            } else {
+                for n in self.max + 1..counter {
+                    let (word, mask) = Self::bit(n);
+                    self.bits[word] &= !mask;
+                }
+            }
+            self.max = counter;
+        }
+        let (word, mask) = Self::bit(counter);
+        self.bits[word] |= mask;
+    }
+}
+
+// 使用预共享密钥的 AEAD 加密，篡改、伪造和重放的报文在交给 kcp 之前就会被丢弃。
+//
+// 每个 AeadCrypt 在创建时随机生成 8 字节的 salt，发送密钥由 HKDF-SHA256 从预共享密钥、
+// 本端的 role 和 salt 派生，nonce 是从 1 开始递增的 64 位计数器，salt 和计数器都以明文放在报文开头。
+// 接收时用对端的 role 和报文中的 salt 派生密钥，第一个通过认证的报文确定对端的 salt，
+// 之后其它 salt 的报文都被丢弃，计数器经过滑动窗口检查，重复或者落后超过 1024 个的报文被丢弃。
+//
+// 限制：
+// - 每个会话的每一端都要使用新的 AeadCrypt，加密和解密通常是两个实例（CryptWriter 和 input 各一个），
+//   它们的 role 相同
+// - 接收端在收到对端第一个报文之前没有状态，旧会话中截获的报文如果先于对端的报文到达，
+//   会让新的接收端锁定到旧的 salt，这个会话将无法建立
+// - 所有会话共用一个预共享密钥，没有前向安全，密钥泄露后所有截获的报文都可以解密；
+//   不同发送方的 salt 相同（概率约为 会话数^2 / 2^65）时才会重用 nonce
+pub struct AeadCrypt {
+    hkdf: Hkdf<Sha256>,
+    algorithm: AeadAlgorithm,
+    role: AeadRole,
+
+    // 发送方向
+    salt: [u8; AEAD_SALT_SIZE],
+    cipher: AeadCipher,
+    counter: u64,
+
+    // 接收方向：对端的 salt、对应的密钥和重放窗口
+    peer: Option<([u8; AEAD_SALT_SIZE], AeadCipher)>,
+    window: ReplayWindow,
+}
+
+impl AeadCrypt {
+    // 16 字节密钥使用 AES-128-GCM，32 字节密钥使用 AES-256-GCM
+    pub fn aes_gcm(key: &[u8], role: AeadRole) -> Result<Self, KcpError> {
+        let algorithm = match key.len() {
+            16 => AeadAlgorithm::Aes128Gcm,
+            32 => AeadAlgorithm::Aes256Gcm,
+            _ => {
+                return Err(KcpError::InvalidConfig(
+                    "aes-gcm key must be 16 or 32 bytes",
+                ))
+            }
+        };
+        Ok(Self::new(key, algorithm, role))
+    }
+
+    pub fn chacha20_poly1305(key: &[u8; 32], role: AeadRole) -> Self {
+        Self::new(key, AeadAlgorithm::ChaCha20Poly1305, role)
+    }
+
+    fn new(key: &[u8], algorithm: AeadAlgorithm, role: AeadRole) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, key);
+        let mut salt = [0u8; AEAD_SALT_SIZE];
+        rand::rng().fill_bytes(&mut salt);
+        let cipher = AeadCipher::derive(&hkdf, algorithm, role, &salt);
+        AeadCrypt {
+            hkdf,
+            algorithm,
+            role,
+            salt,
+            cipher,
+            counter: 0,
+            peer: None,
+            window: ReplayWindow::new(),
+        }
+    }
+
+    pub fn role(&self) -> AeadRole {
+        self.role
+    }
+}
+
+fn aead_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+impl PacketCrypt for AeadCrypt {
+    fn overhead(&self) -> usize {
+        AEAD_SALT_SIZE + AEAD_COUNTER_SIZE + AEAD_TAG_SIZE
+    }
+
+    fn encrypt(&mut self, pkt: &[u8]) -> Vec<u8> {
+        // 64 位计数器在实际使用中不会用完
+        self.counter += 1;
+        let nonce = aead_nonce(self.counter);
+        let nonce_ref = GenericArray::from_slice(&nonce);
+        // 只有明文超过 2^36 字节时才会失败
+        let sealed = match &self.cipher {
+            AeadCipher::Aes128Gcm(c) => c.encrypt(nonce_ref, pkt),
+            AeadCipher::Aes256Gcm(c) => c.encrypt(nonce_ref, pkt),
+            AeadCipher::ChaCha20Poly1305(c) => c.encrypt(nonce_ref, pkt),
+        }
+        .expect("packet too large to encrypt");
+
+        let mut out = Vec::with_capacity(AEAD_SALT_SIZE + AEAD_COUNTER_SIZE + sealed.len());
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.counter.to_be_bytes());
+        out.extend_from_slice(&sealed);
+        out
+    }
+
+    fn decrypt(&mut self, pkt: &[u8]) -> Result<Vec<u8>, KcpError> {
+        if pkt.len() < self.overhead() {
+            return Err(KcpError::Truncated);
+        }
+        let (salt, rest) = pkt.split_at(AEAD_SALT_SIZE);
+        let (counter, sealed) = rest.split_at(AEAD_COUNTER_SIZE);
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+        if !self.window.check(counter) {
+            return Err(KcpError::Decrypt);
+        }
+
+        // 第一个报文按照其中的 salt 派生密钥，通过认证后才锁定对端的 salt
+        let derived = match &self.peer {
+            Some((peer_salt, _)) if peer_salt[..] == salt[..] => None,
+            Some(_) => return Err(KcpError::Decrypt),
+            None => Some(AeadCipher::derive(
+                &self.hkdf,
+                self.algorithm,
+                self.role.peer(),
+                salt,
+            )),
+        };
+        let cipher = match &derived {
+            Some(cipher) => cipher,
+            None => &self.peer.as_ref().unwrap().1,
+        };
+        let nonce = aead_nonce(counter);
+        let nonce = GenericArray::from_slice(&nonce);
+        let plain = match cipher {
+            AeadCipher::Aes128Gcm(c) => c.decrypt(nonce, sealed),
+            AeadCipher::Aes256Gcm(c) => c.decrypt(nonce, sealed),
+            AeadCipher::ChaCha20Poly1305(c) => c.decrypt(nonce, sealed),
+        }
+        .map_err(|_| KcpError::Decrypt)?;
+
+        if let Some(cipher) = derived {
+            self.peer = Some((salt.try_into().unwrap(), cipher));
+        }
+        self.window.update(counter);
+        Ok(plain)
+    }
+}
+
+enum BlockCipher {
+    Aes128(Box<Aes128>),
+    Aes192(Box<Aes192>),
+    Aes256(Box<Aes256>),
+}
+
+impl BlockCipher {
+    fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            BlockCipher::Aes128(c) => c.encrypt_block(block),
+            BlockCipher::Aes192(c) => c.encrypt_block(block),
+            BlockCipher::Aes256(c) => c.encrypt_block(block),
+        }
+    }
+
+    // CFB 模式，与 kcp-go 的 encrypt16/decrypt16 一致，最后不足一个分组的部分直接异或
+    fn cfb(&self, iv: &[u8; 16], data: &mut [u8], encrypt: bool) {
+        let mut stream = *iv;
+        self.encrypt_block(&mut stream);
+        for chunk in data.chunks_mut(16) {
+            let mut next = [0u8; 16];
+            for (i, b) in chunk.iter_mut().enumerate() {
+                let c = if encrypt { *b ^ stream[i] } else { *b };
+                *b ^= stream[i];
+                next[i] = c;
+            }
+            if chunk.len() == 16 {
+                stream = next;
+                self.encrypt_block(&mut stream);
+            }
+        }
+    }
+}
+
+// 与 kcp-go 兼容的 AES 加密：nonce(16) + crc32(4) + kcp 报文，整个报文使用固定 IV 的 CFB 加密。
+// crc32 只能发现传输错误，不能防止篡改，新的部署应该使用 AeadCrypt
+//
+// 密钥与 kcp-go 的 NewAESBlockCrypt 相同，通常是 pbkdf2 从密码生成的 16/24/32 字节
+pub struct BlockCrypt {
+    cipher: BlockCipher,
+}
+
+impl BlockCrypt {
+    pub fn aes(key: &[u8]) -> Result<Self, KcpError> {
+        let cipher = match key.len() {
+            16 => BlockCipher::Aes128(Box::new(Aes128::new(GenericArray::from_slice(key)))),
+            24 => BlockCipher::Aes192(Box::new(Aes192::new(GenericArray::from_slice(key)))),
+            32 => BlockCipher::Aes256(Box::new(Aes256::new(GenericArray::from_slice(key)))),
+            _ => {
+                return Err(KcpError::InvalidConfig(
+                    "aes key must be 16, 24 or 32 bytes",
+                ))
+            }
+        };
+        Ok(BlockCrypt { cipher })
+    }
+}
+
+impl PacketCrypt for BlockCrypt {
+    fn overhead(&self) -> usize {
+        BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE
+    }
+
+    fn encrypt(&mut self, pkt: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE + pkt.len()];
+        rand::rng().fill_bytes(&mut out[..BLOCK_NONCE_SIZE]);
+        out[BLOCK_NONCE_SIZE..BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE]
+            .copy_from_slice(&crc32fast::hash(pkt).to_le_bytes());
+        out[BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE..].copy_from_slice(pkt);
+        self.cipher.cfb(&INITIAL_VECTOR, &mut out, true);
+        out
+    }
+
+    fn decrypt(&mut self, pkt: &[u8]) -> Result<Vec<u8>, KcpError> {
+        if pkt.len() < BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE {
+            return Err(KcpError::Truncated);
+        }
+        let mut data = pkt.to_vec();
+        self.cipher.cfb(&INITIAL_VECTOR, &mut data, false);
+        let crc = &data[BLOCK_NONCE_SIZE..BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE];
+        let crc = u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        let payload = data.split_off(BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE);
+        if crc32fast::hash(&payload) != crc {
+            return Err(KcpError::Decrypt);
+        }
+        Ok(payload)
+    }
+}
+
+// 在 output 之前加密，每次 write 都被当作一个完整的报文
+//
+// let output = CryptWriter::new(socket, AeadCrypt::aes_gcm(&key, AeadRole::Client)?);
+// let mut kcp = Kcp::ickp_create(output, conv);
+// kcp.ikcp_setmtu(1400 - kcp.output().crypt().overhead() as u32)?;
+pub struct CryptWriter<W: Write, C: PacketCrypt> {
+    inner: W,
+    crypt: C,
+}
+
+impl<W: Write, C: PacketCrypt> CryptWriter<W, C> {
+    pub fn new(inner: W, crypt: C) -> Self {
+        CryptWriter { inner, crypt }
+    }
+
+    pub fn crypt(&self) -> &C {
+        &self.crypt
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write, C: PacketCrypt> Write for CryptWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pkt = self.crypt.encrypt(buf);
+        self.inner.write_all(&pkt)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Datagrams(Vec<Vec<u8>>);
+
+    impl Write for Datagrams {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn cfb_matches_nist_vectors() {
+        // NIST SP 800-38A F.3.13 CFB128-AES128.Encrypt，最后截断为不完整的分组
+        let cipher = BlockCrypt::aes(&hex("2b7e151628aed2a6abf7158809cf4f3c")).unwrap();
+        let iv: [u8; 16] = hex("000102030405060708090a0b0c0d0e0f").try_into().unwrap();
+        let plain =
+            hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e5130c81c46a35ce411");
+        let expected =
+            hex("3b3fd92eb72dad20333449f8e83cfb4ac8a64537a0b3a93fcde3cdad9f1ce58b26751f67a3cbb140");
+        let mut data = plain.clone();
+        cipher.cipher.cfb(&iv, &mut data, true);
+        assert_eq!(data, expected);
+        cipher.cipher.cfb(&iv, &mut data, false);
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn block_crypt_round_trip_and_crc() {
+        let mut crypt = BlockCrypt::aes(&[7; 32]).unwrap();
+        let pkt = crypt.encrypt(b"hello kcp");
+        assert_eq!(pkt.len(), 9 + crypt.overhead());
+        assert_eq!(crypt.decrypt(&pkt).unwrap(), b"hello kcp");
+
+        // 相同的明文由于 nonce 不同得到不同的密文
+        assert_ne!(crypt.encrypt(b"hello kcp"), pkt);
+
+        let mut bad = pkt.clone();
+        bad[25] ^= 1;
+        assert!(matches!(crypt.decrypt(&bad), Err(KcpError::Decrypt)));
+        assert!(matches!(
+            crypt.decrypt(&pkt[..10]),
+            Err(KcpError::Truncated)
+        ));
+        assert!(BlockCrypt::aes(&[0; 20]).is_err());
+    }
+
+    #[test]
+    fn aead_rejects_tampering() {
+        let pairs = [
+            (
+                AeadCrypt::aes_gcm(&[1; 16], AeadRole::Client).unwrap(),
+                AeadCrypt::aes_gcm(&[1; 16], AeadRole::Server).unwrap(),
+            ),
+            (
+                AeadCrypt::aes_gcm(&[2; 32], AeadRole::Client).unwrap(),
+                AeadCrypt::aes_gcm(&[2; 32], AeadRole::Server).unwrap(),
+            ),
+            (
+                AeadCrypt::chacha20_poly1305(&[3; 32], AeadRole::Client),
+                AeadCrypt::chacha20_poly1305(&[3; 32], AeadRole::Server),
+            ),
+        ];
+        for (mut client, mut server) in pairs {
+            let pkt = client.encrypt(b"secret");
+            assert_eq!(pkt.len(), 6 + client.overhead());
+            for i in 0..pkt.len() {
+                let mut bad = pkt.clone();
+                bad[i] ^= 0x80;
+                assert!(matches!(server.decrypt(&bad), Err(KcpError::Decrypt)));
+            }
+            assert_eq!(server.decrypt(&pkt).unwrap(), b"secret");
+        }
+
+        // 密钥不同
+        let pkt = AeadCrypt::aes_gcm(&[1; 16], AeadRole::Client)
+            .unwrap()
+            .encrypt(b"secret");
+        let mut other = AeadCrypt::aes_gcm(&[9; 16], AeadRole::Server).unwrap();
+        assert!(matches!(other.decrypt(&pkt), Err(KcpError::Decrypt)));
+    }
+
+    #[test]
+    fn aead_rejects_replay_and_reflection() {
+        let key = [4; 32];
+        let mut client = AeadCrypt::chacha20_poly1305(&key, AeadRole::Client);
+        let mut server = AeadCrypt::chacha20_poly1305(&key, AeadRole::Server);
+        let pkts: Vec<Vec<u8>> = (0..2000u32)
+            .map(|i| client.encrypt(&i.to_le_bytes()))
+            .collect();
+
+        // 窗口内乱序的报文可以接受，重复的报文被丢弃
+        assert!(server.decrypt(&pkts[10]).is_ok());
+        assert!(server.decrypt(&pkts[3]).is_ok());
+        assert!(matches!(server.decrypt(&pkts[3]), Err(KcpError::Decrypt)));
+        assert!(matches!(server.decrypt(&pkts[10]), Err(KcpError::Decrypt)));
+        assert!(server.decrypt(&pkts[1500]).is_ok());
+        // 落后超过窗口的报文被丢弃
+        assert!(matches!(server.decrypt(&pkts[4]), Err(KcpError::Decrypt)));
+        assert!(server.decrypt(&pkts[1000]).is_ok());
+
+        // 客户端自己发出的报文反射回客户端无法通过认证
+        let mut reflected = AeadCrypt::chacha20_poly1305(&key, AeadRole::Client);
+        assert!(matches!(
+            reflected.decrypt(&pkts[0]),
+            Err(KcpError::Decrypt)
+        ));
+
+        // 锁定对端的 salt 之后，其它会话的报文被丢弃
+        let other = AeadCrypt::chacha20_poly1305(&key, AeadRole::Client).encrypt(b"other");
+        assert!(matches!(server.decrypt(&other), Err(KcpError::Decrypt)));
+    }
+
+    #[test]
+    fn kcp_over_encrypted_output() {
+        let key = [5; 32];
+        let writer = CryptWriter::new(
+            Datagrams::default(),
+            AeadCrypt::chacha20_poly1305(&key, AeadRole::Client),
+        );
+        let mut a = Kcp::ickp_create(writer, 1);
+        let overhead = a.output().crypt().overhead() as u32;
+        a.ikcp_setmtu(1400 - overhead).unwrap();
+        a.ikcp_nodelay(true, 10, 0, true);
+        let mut b = Kcp::ickp_create((), 1);
+        let mut crypt = AeadCrypt::chacha20_poly1305(&key, AeadRole::Server);
+
+        a.ikcp_send(&[42; 3000]).unwrap();
+        a.ikcp_update(0).unwrap();
+        let pkts = std::mem::take(&mut a.output_mut().get_mut().0);
+        assert_eq!(pkts.len(), 3);
+        for pkt in &pkts {
+            assert!(pkt.len() <= 1400);
+            // 明文中的 conv 不可见
+            assert_ne!(&pkt[..4], &1u32.to_le_bytes());
+            crypt.input(&mut b, pkt).unwrap();
+        }
+        let mut buf = [0; 3000];
+        assert_eq!(b.ikcp_recv(&mut buf).unwrap(), 3000);
+        assert!(buf.iter().all(|&x| x == 42));
+
+        // 伪造的报文不会进入 kcp
+        let mut forged = pkts[0].clone();
+        forged[20] ^= 1;
+        assert!(crypt.input(&mut b, &forged).is_err());
+        assert_eq!(b.stats().in_pkts, 3);
+    }
+
+    // 与 kcp-go 相同的分层：kcp -> fec -> 加密 -> socket
+    #[cfg(feature = "fec")]
+    #[test]
+    fn fec_inside_block_crypt() {
+        use crate::fec::{FecDecoder, FecWriter, FEC_OVERHEAD};
+
+        let key = [6; 16];
+        let writer = FecWriter::new(
+            CryptWriter::new(Datagrams::default(), BlockCrypt::aes(&key).unwrap()),
+            2,
+            1,
+        )
+        .unwrap();
+        let mut a = Kcp::ickp_create(writer, 1);
+        a.ikcp_setmtu(1400 - (FEC_OVERHEAD + BLOCK_NONCE_SIZE + BLOCK_CRC_SIZE) as u32)
+            .unwrap();
+        a.ikcp_nodelay(true, 10, 0, true);
+        let mut b = Kcp::ickp_create((), 1);
+        let mut crypt = BlockCrypt::aes(&key).unwrap();
+        let mut decoder = FecDecoder::new(2, 1).unwrap();
+
+        a.ikcp_send(&[1; 2000]).unwrap();
+        a.ikcp_update(0).unwrap();
+        let pkts = std::mem::take(&mut a.output_mut().get_mut().get_mut().0);
+        assert_eq!(pkts.len(), 3);
+        // 丢掉第一个数据报文，由校验报文恢复
+        for pkt in &pkts[1..] {
+            assert!(pkt.len() <= 1400);
+            let pkt = crypt.decrypt(pkt).unwrap();
+            decoder.input(&mut b, &pkt).unwrap();
+        }
+        let mut buf = [0; 2000];
+        assert_eq!(b.ikcp_recv(&mut buf).unwrap(), 2000);
+    }
+}
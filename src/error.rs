@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum KcpError {
+    // 接收队列中没有完整的消息
+    Empty,
+
+    // 用户提供的缓冲区不足以容纳下一条消息
+    BufferTooSmall { needed: usize },
+
+    // 发送了空的消息
+    EmptyPayload,
+
+    // 报文的 conv 与当前会话不一致
+    ConvMismatch { expected: u32, got: u32 },
+
+    // 报文长度不足一个头部，或者 len 字段超出了剩余数据
+    Truncated,
+
+    // 未知的 cmd
+    UnknownCommand(u8),
+
+    // 消息需要的分片数不小于 IKCP_WND_RCV，对端的接收窗口放不下
+    TooManyFragments,
+
+    // mtu 过小
+    InvalidMtu(u32),
+
+    // KcpConfig 中的参数不合法
+    InvalidConfig(&'static str),
+
+    // fec 报文的 flag 不是数据或校验
+    InvalidFec(u16),
+
+    // 报文解密或者校验失败
+    Decrypt,
+
+    // ChunkedKcp 收到的消息超过了 recv_limit
+    MessageTooLarge { limit: usize },
+
+    // ChunkedKcp 收到的 kcp 消息没有合法的头部
+    InvalidChunk,
+
+    // 重传次数超过 dead_link，连接已断开
+    DeadLink,
+
+    // output 写入失败
+    Output(io::Error),
+}
+
+impl fmt::Display for KcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KcpError::Empty => write!(f, "no complete message available"),
+            KcpError::BufferTooSmall { needed } => {
+                write!(f, "buffer too small, {} bytes needed", needed)
+            }
+            KcpError::EmptyPayload => write!(f, "cannot send an empty message"),
+            KcpError::ConvMismatch { expected, got } => {
+                write!(f, "conv mismatch, expected {} got {}", expected, got)
+            }
+            KcpError::Truncated => write!(f, "truncated segment"),
+            KcpError::UnknownCommand(cmd) => write!(f, "unknown command {}", cmd),
+            KcpError::TooManyFragments => write!(f, "message needs too many fragments"),
+            KcpError::InvalidMtu(mtu) => write!(f, "invalid mtu {}", mtu),
+            KcpError::InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            KcpError::InvalidFec(flag) => write!(f, "invalid fec flag {:#x}", flag),
+            KcpError::Decrypt => write!(f, "packet decryption failed"),
+            KcpError::MessageTooLarge { limit } => {
+                write!(f, "message exceeds the {} bytes limit", limit)
+            }
+            KcpError::InvalidChunk => write!(f, "invalid chunk header"),
+            KcpError::DeadLink => write!(f, "link is dead"),
+            KcpError::Output(e) => write!(f, "output error: {}", e),
+        }
+    }
+}
+
+impl Error for KcpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KcpError::Output(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KcpError {
+    fn from(e: io::Error) -> Self {
+        KcpError::Output(e)
+    }
+}
+
+impl From<KcpError> for io::Error {
+    fn from(e: KcpError) -> Self {
+        match e {
+            KcpError::Empty => io::ErrorKind::WouldBlock.into(),
+            KcpError::DeadLink => io::Error::new(io::ErrorKind::ConnectionAborted, e),
+            KcpError::Output(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
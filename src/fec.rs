@@ -0,0 +1,474 @@
+use crate::error::KcpError;
+use crate::kcp::Kcp;
+use bytes::{BufMut, Bytes, BytesMut};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+// kcp-go 的 fec 头部：seqid(4) + flag(2)，数据分片之后还有 size(2)
+const FEC_HEADER_SIZE: usize = 6;
+
+// 数据分片额外的开销，使用 fec 时 kcp 的 mtu 需要减去这个值
+pub const FEC_OVERHEAD: usize = FEC_HEADER_SIZE + 2;
+
+const TYPE_DATA: u16 = 0xf1;
+const TYPE_PARITY: u16 = 0xf2;
+
+// 解码端最多同时保留的分组数
+const RX_GROUPS: usize = 3;
+
+fn new_codec(data_shards: usize, parity_shards: usize) -> Result<ReedSolomon, KcpError> {
+    if data_shards == 0 || parity_shards == 0 {
+        return Err(KcpError::InvalidConfig(
+            "fec data and parity shards must be at least 1",
+        ));
+    }
+    ReedSolomon::new(data_shards, parity_shards)
+        .map_err(|_| KcpError::InvalidConfig("too many fec shards"))
+}
+
+// 把 kcp 的报文按 data_shards 个分为一组，每组生成 parity_shards 个校验报文，
+// 报文格式与 kcp-go 兼容
+//
+// 数据报文：seqid(u32) flag(0xf1) size(u16，包含自身) kcp 报文
+// 校验报文：seqid(u32) flag(0xf2) 校验数据
+pub struct FecEncoder {
+    data_shards: usize,
+    parity_shards: usize,
+    codec: ReedSolomon,
+
+    // 下一个报文的 seqid，在 paws 处回绕，保证每组的 seqid 连续
+    next: u32,
+    paws: u32,
+
+    // 当前分组中的数据分片（size + kcp 报文）
+    shards: Vec<Vec<u8>>,
+}
+
+impl FecEncoder {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, KcpError> {
+        let codec = new_codec(data_shards, parity_shards)?;
+        let shard_size = (data_shards + parity_shards) as u32;
+        Ok(FecEncoder {
+            data_shards,
+            parity_shards,
+            codec,
+            next: 0,
+            paws: u32::MAX / shard_size * shard_size,
+            shards: Vec::with_capacity(data_shards),
+        })
+    }
+
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn parity_shards(&self) -> usize {
+        self.parity_shards
+    }
+
+    fn header(&mut self, buf: &mut BytesMut, flag: u16) {
+        buf.put_u32_le(self.next);
+        buf.put_u16_le(flag);
+        self.next = (self.next + 1) % self.paws;
+    }
+
+    // 编码一个 kcp 报文，out 中依次追加数据报文以及分组完成时的校验报文
+    pub fn encode(&mut self, pkt: &[u8], out: &mut Vec<Bytes>) -> Result<(), KcpError> {
+        if pkt.len() + 2 > u16::MAX as usize {
+            return Err(KcpError::InvalidConfig("packet too large for fec"));
+        }
+        let mut buf = BytesMut::with_capacity(FEC_OVERHEAD + pkt.len());
+        self.header(&mut buf, TYPE_DATA);
+        buf.put_u16_le((pkt.len() + 2) as u16);
+        buf.put_slice(pkt);
+        self.shards.push(buf[FEC_HEADER_SIZE..].to_vec());
+        out.push(buf.freeze());
+
+        if self.shards.len() < self.data_shards {
+            return Ok(());
+        }
+
+        // 数据分片补零到相同长度后计算校验分片
+        let max_size = self.shards.iter().map(|s| s.len()).max().unwrap_or(0);
+        let mut shards = std::mem::take(&mut self.shards);
+        for shard in shards.iter_mut() {
+            shard.resize(max_size, 0);
+        }
+        shards.resize(self.data_shards + self.parity_shards, vec![0; max_size]);
+        self.codec
+            .encode(&mut shards)
+            .map_err(|_| KcpError::InvalidConfig("fec encode failed"))?;
+
+        for parity in &shards[self.data_shards..] {
+            let mut buf = BytesMut::with_capacity(FEC_HEADER_SIZE + max_size);
+            self.header(&mut buf, TYPE_PARITY);
+            buf.put_slice(parity);
+            out.push(buf.freeze());
+        }
+        shards.clear();
+        self.shards = shards;
+        Ok(())
+    }
+}
+
+struct Group {
+    // 分组中第一个报文的 seqid
+    start: u32,
+    shards: Vec<Option<Vec<u8>>>,
+    received: usize,
+    // 已经收齐或者恢复过，不再处理
+    done: bool,
+}
+
+// 解码 kcp-go 格式的 fec 报文，数据报文立即交给 kcp，
+// 同一分组收到 data_shards 个报文后恢复丢失的数据报文
+pub struct FecDecoder {
+    data_shards: usize,
+    parity_shards: usize,
+    codec: ReedSolomon,
+    groups: VecDeque<Group>,
+}
+
+impl FecDecoder {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, KcpError> {
+        Ok(FecDecoder {
+            data_shards,
+            parity_shards,
+            codec: new_codec(data_shards, parity_shards)?,
+            groups: VecDeque::new(),
+        })
+    }
+
+    // 解码一个 fec 报文，out 中追加可以交给 ikcp_input 的 kcp 报文
+    pub fn decode(&mut self, pkt: &[u8], out: &mut Vec<Bytes>) -> Result<(), KcpError> {
+        if pkt.len() < FEC_HEADER_SIZE {
+            return Err(KcpError::Truncated);
+        }
+        let seqid = u32::from_le_bytes([pkt[0], pkt[1], pkt[2], pkt[3]]);
+        let flag = u16::from_le_bytes([pkt[4], pkt[5]]);
+        if flag != TYPE_DATA && flag != TYPE_PARITY {
+            return Err(KcpError::InvalidFec(flag));
+        }
+        // 校验分片至少与数据分片的 size 字段一样长，否则恢复出的数据分片读不出 size
+        if pkt.len() < FEC_OVERHEAD {
+            return Err(KcpError::Truncated);
+        }
+        if flag == TYPE_DATA {
+            out.push(Bytes::copy_from_slice(&pkt[FEC_OVERHEAD..]));
+        }
+
+        let shard_size = (self.data_shards + self.parity_shards) as u32;
+        let start = seqid - seqid % shard_size;
+        let index = (seqid % shard_size) as usize;
+        let group = match self.groups.iter().position(|g| g.start == start) {
+            Some(i) => &mut self.groups[i],
+            None => {
+                if self.groups.len() >= RX_GROUPS {
+                    self.groups.pop_front();
+                }
+                self.groups.push_back(Group {
+                    start,
+                    shards: vec![None; shard_size as usize],
+                    received: 0,
+                    done: false,
+                });
+                self.groups.back_mut().unwrap()
+            }
+        };
+        if group.done || group.shards[index].is_some() {
+            return Ok(());
+        }
+        group.shards[index] = Some(pkt[FEC_HEADER_SIZE..].to_vec());
+        group.received += 1;
+        if group.received < self.data_shards {
+            return Ok(());
+        }
+        group.done = true;
+
+        let missing: Vec<usize> = (0..self.data_shards)
+            .filter(|&i| group.shards[i].is_none())
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        // 补零到相同长度后恢复数据分片
+        let max_size = group
+            .shards
+            .iter()
+            .flatten()
+            .map(|s| s.len())
+            .max()
+            .unwrap_or(0);
+        for shard in group.shards.iter_mut().flatten() {
+            shard.resize(max_size, 0);
+        }
+        if self.codec.reconstruct_data(&mut group.shards).is_err() {
+            return Ok(());
+        }
+        for i in missing {
+            let shard = group.shards[i].as_ref().unwrap();
+            if shard.len() < 2 {
+                continue;
+            }
+            let size = u16::from_le_bytes([shard[0], shard[1]]) as usize;
+            if size >= 2 && size <= shard.len() {
+                out.push(Bytes::copy_from_slice(&shard[2..size]));
+            }
+        }
+        Ok(())
+    }
+
+    // 解码 fec 报文并把得到的 kcp 报文交给 input_bytes
+    pub fn input<W>(&mut self, kcp: &mut Kcp<W>, pkt: &[u8]) -> Result<(), KcpError> {
+        let mut out = Vec::new();
+        self.decode(pkt, &mut out)?;
+        for pkt in out {
+            kcp.input_bytes(pkt)?;
+        }
+        Ok(())
+    }
+}
+
+// 在 output 之前加上 fec 编码，配合 Kcp 的 Write 接口使用。
+// 每次 write 都被当作一个完整的 kcp 报文
+//
+// let output = FecWriter::new(socket, 10, 3)?;
+// let mut kcp = Kcp::ickp_create(output, conv);
+// kcp.ikcp_setmtu(1400 - FEC_OVERHEAD as u32)?;
+pub struct FecWriter<W: Write> {
+    inner: W,
+    encoder: FecEncoder,
+    out: Vec<Bytes>,
+}
+
+impl<W: Write> FecWriter<W> {
+    pub fn new(inner: W, data_shards: usize, parity_shards: usize) -> Result<Self, KcpError> {
+        Ok(FecWriter {
+            inner,
+            encoder: FecEncoder::new(data_shards, parity_shards)?,
+            out: Vec::new(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    // 按顺序写出 out 中的报文，写入失败的报文和它后面的报文留在 out 中
+    fn write_pending(&mut self) -> io::Result<()> {
+        let mut sent = 0;
+        let mut result = Ok(());
+        for pkt in &self.out {
+            if let Err(e) = self.inner.write_all(pkt) {
+                result = Err(e);
+                break;
+            }
+            sent += 1;
+        }
+        self.out.drain(..sent);
+        result
+    }
+}
+
+impl<W: Write> Write for FecWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // 先重试上次没有写出去的报文，失败时 buf 还没有编码，kcp 重试时不会重复占用 seqid
+        self.write_pending()?;
+        self.encoder.encode(buf, &mut self.out)?;
+        // 数据分片已经编码，写入失败的报文留到下一次 write 或 flush 时重试
+        let _ = self.write_pending();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 把每次 write 记录为一个报文
+    #[derive(Default)]
+    struct Datagrams(Vec<Vec<u8>>);
+
+    impl Write for Datagrams {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // fail 为 Some(n) 时再成功写入 n 个报文后返回一次 WouldBlock
+    #[derive(Default)]
+    struct FlakyDatagrams {
+        fail: Option<usize>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl Write for FlakyDatagrams {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.fail {
+                Some(0) => {
+                    self.fail = None;
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Some(n) => self.fail = Some(n - 1),
+                None => {}
+            }
+            self.sent.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writer_keeps_unsent_packets() {
+        let seqids = |w: &FecWriter<FlakyDatagrams>| -> Vec<u32> {
+            w.get_ref()
+                .sent
+                .iter()
+                .map(|p| u32::from_le_bytes([p[0], p[1], p[2], p[3]]))
+                .collect()
+        };
+        let mut writer = FecWriter::new(FlakyDatagrams::default(), 2, 1).unwrap();
+        writer.write_all(b"a").unwrap();
+
+        // 数据分片写出后校验分片写入失败，数据分片已经被接受，返回 Ok
+        writer.get_mut().fail = Some(1);
+        assert_eq!(writer.write(b"b").unwrap(), 1);
+        assert_eq!(seqids(&writer), [0, 1]);
+
+        // 留下的校验分片写入失败时不编码新的数据，重试不会重复占用 seqid
+        writer.get_mut().fail = Some(0);
+        assert!(writer.write(b"c").is_err());
+        writer.write_all(b"c").unwrap();
+        assert_eq!(seqids(&writer), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn packet_layout_matches_kcp_go() {
+        let mut encoder = FecEncoder::new(2, 1).unwrap();
+        let mut out = Vec::new();
+        encoder.encode(b"ab", &mut out).unwrap();
+        encoder.encode(b"c", &mut out).unwrap();
+        assert_eq!(out.len(), 3);
+        assert_eq!(&out[0][..], &[0, 0, 0, 0, 0xf1, 0, 4, 0, b'a', b'b']);
+        assert_eq!(&out[1][..], &[1, 0, 0, 0, 0xf1, 0, 3, 0, b'c']);
+        // 校验分片没有 size 字段，长度为最长的数据分片
+        assert_eq!(&out[2][..6], &[2, 0, 0, 0, 0xf2, 0]);
+        assert_eq!(out[2].len(), 6 + 4);
+
+        // seqid 在 paws 处回绕
+        encoder.next = encoder.paws - 1;
+        out.clear();
+        encoder.encode(b"d", &mut out).unwrap();
+        assert_eq!(&out[0][..4], &(u32::MAX / 3 * 3 - 1).to_le_bytes());
+        encoder.encode(b"e", &mut out).unwrap();
+        assert_eq!(&out[1][..4], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn recovers_lost_data_shards() {
+        let mut encoder = FecEncoder::new(4, 2).unwrap();
+        let mut decoder = FecDecoder::new(4, 2).unwrap();
+        let payloads: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; i as usize * 10 + 1]).collect();
+        let mut pkts = Vec::new();
+        for p in &payloads {
+            encoder.encode(p, &mut pkts).unwrap();
+        }
+        assert_eq!(pkts.len(), 12);
+
+        // 第一组丢两个数据分片，第二组丢一个数据分片和一个校验分片
+        let mut out = Vec::new();
+        for (i, pkt) in pkts.iter().enumerate() {
+            if ![0, 2, 7, 10].contains(&i) {
+                decoder.decode(pkt, &mut out).unwrap();
+            }
+        }
+        let mut got: Vec<Vec<u8>> = out.iter().map(|b| b.to_vec()).collect();
+        got.sort();
+        assert_eq!(got, payloads);
+
+        // 非法的报文
+        assert!(matches!(
+            decoder.decode(&[0, 0, 0], &mut out),
+            Err(KcpError::Truncated)
+        ));
+        assert!(matches!(
+            decoder.decode(&[0, 0, 0, 0, 0xf3, 0], &mut out),
+            Err(KcpError::InvalidFec(0xf3))
+        ));
+        assert!(FecEncoder::new(0, 3).is_err());
+    }
+
+    #[test]
+    fn short_parity_shard_is_rejected() {
+        // 校验分片比数据分片更多时，一个校验报文就会触发恢复，过短的校验分片不能导致 panic
+        let mut decoder = FecDecoder::new(1, 1).unwrap();
+        let mut out = Vec::new();
+        assert!(matches!(
+            decoder.decode(&[1, 0, 0, 0, 0xf2, 0, 7], &mut out),
+            Err(KcpError::Truncated)
+        ));
+        assert!(out.is_empty());
+
+        // 正常长度的校验分片仍然可以恢复
+        let mut encoder = FecEncoder::new(1, 1).unwrap();
+        let mut pkts = Vec::new();
+        encoder.encode(b"x", &mut pkts).unwrap();
+        decoder.decode(&pkts[1], &mut out).unwrap();
+        assert_eq!(&out[0][..], b"x");
+    }
+
+    #[test]
+    fn kcp_over_lossy_fec_link() {
+        let writer = FecWriter::new(Datagrams::default(), 10, 3).unwrap();
+        let mut a = Kcp::ickp_create(writer, 1);
+        let mut b = Kcp::ickp_create(Datagrams::default(), 1);
+        a.ikcp_setmtu(1400 - FEC_OVERHEAD as u32).unwrap();
+        a.ikcp_nodelay(true, 10, 0, true);
+        a.ikcp_wndsize(128, 128);
+        let mut decoder = FecDecoder::new(10, 3).unwrap();
+
+        for i in 0..20u8 {
+            a.ikcp_send(&[i; 1000]).unwrap();
+        }
+        a.ikcp_update(0).unwrap();
+        let pkts = std::mem::take(&mut a.output_mut().get_mut().0);
+        // 20 个数据报文和 6 个校验报文
+        assert_eq!(pkts.len(), 26);
+        assert!(pkts.iter().all(|p| p.len() <= 1400));
+
+        // 每组丢 3 个报文，全部由 fec 恢复，不需要重传
+        for (i, pkt) in pkts.iter().enumerate() {
+            if i % 13 >= 3 {
+                decoder.input(&mut b, pkt).unwrap();
+            }
+        }
+        let mut buf = [0; 1000];
+        for i in 0..20u8 {
+            assert_eq!(b.ikcp_recv(&mut buf).unwrap(), 1000);
+            assert_eq!(buf, [i; 1000]);
+        }
+    }
+}
@@ -1,27 +1,32 @@
-use bytes::{Buf, BufMut, BytesMut};
+use crate::config::KcpConfig;
+use crate::congestion::{AckEvent, CongestionController, KcpCongestion};
+use crate::error::KcpError;
+use crate::segment::{
+    SegmentHeader, SegmentRef, Segments, IKCP_CMD_ACK, IKCP_CMD_PUSH, IKCP_CMD_WASK, IKCP_CMD_WINS,
+    IKCP_OVERHEAD,
+};
+use crate::state::KcpState;
+use crate::stats::KcpStats;
+use bytes::{BufMut, Bytes, BytesMut};
 use std::cmp::{max, min};
 use std::collections::VecDeque;
-use std::io::{Cursor, Read, Write};
+use std::io::Write;
 
 const IKCP_RTO_NDL: u32 = 30; // no delay min rto
 const IKCP_RTO_MIN: u32 = 100; // normal min rto
 const IKCP_RTO_DEF: u32 = 200;
 const IKCP_RTO_MAX: u32 = 60000;
-const IKCP_CMD_PUSH: u8 = 81; // cmd: push data
-const IKCP_CMD_ACK: u8 = 82; // cmd: ack
-const IKCP_CMD_WASK: u8 = 83; // cmd: window probe (ask)
-const IKCP_CMD_WINS: u8 = 84; // cmd: window size (tell)
 const IKCP_ASK_SEND: u32 = 1; // need to send IKCP_CMD_WASK
 const IKCP_ASK_TELL: u32 = 2; // need to send IKCP_CMD_WINS
-const IKCP_WND_SND: u32 = 32;
-const IKCP_WND_RCV: u32 = 128; // must >= max fragment size
-const IKCP_MTU_DEF: u32 = 1400;
-const IKCP_INTERVAL: u32 = 100;
-const IKCP_OVERHEAD: u32 = 24;
-const IKCP_THRESH_INIT: u32 = 2;
-const IKCP_THRESH_MIN: u32 = 2;
+pub(crate) const IKCP_WND_SND: u32 = 32;
+pub(crate) const IKCP_WND_RCV: u32 = 128; // must >= max fragment size
+pub(crate) const IKCP_MTU_DEF: u32 = 1400;
+pub(crate) const IKCP_INTERVAL: u32 = 100;
 const IKCP_PROBE_INIT: u32 = 7000; // 7 secs to probe window size
 const IKCP_PROBE_LIMIT: u32 = 120000; // up to 120 secs to probe window
+pub(crate) const IKCP_DEADLINK: u32 = 20;
+const IKCP_CHECK_IDLE: u32 = 10000; // max wait between updates when nothing is pending
+const IKCP_FASTACK_LIMIT: u32 = 5; // max times to trigger fastack
 
 #[derive(Default)]
 #[repr(C)]
@@ -62,26 +67,48 @@ struct Segment {
     //发送分片的次数，每发送一次加一。发送的次数对RTO的计算有影响，但是比TCP来说，影响会小一些，计算思想类似
     xmit: u32,
 
-    data: Vec<u8>,
+    data: Bytes,
 }
 
 impl Segment {
     pub fn encode(&self, buf: &mut BytesMut) {
-        buf.put_u32_le(self.conv);
-        buf.put_u8(self.cmd);
-        buf.put_u8(self.frg);
-        buf.put_u16_le(self.wnd);
-        buf.put_u32_le(self.ts);
-        buf.put_u32_le(self.sn);
-        buf.put_u32_le(self.una);
-        buf.put_u32_le(self.len);
+        SegmentHeader {
+            conv: self.conv,
+            cmd: self.cmd,
+            frg: self.frg,
+            wnd: self.wnd,
+            ts: self.ts,
+            sn: self.sn,
+            una: self.una,
+            len: self.len,
+        }
+        .encode(buf);
         buf.put_slice(&self.data)
     }
 }
 
+// Kcp::messages 返回的迭代器
+pub struct Messages<'a, W> {
+    kcp: &'a mut Kcp<W>,
+}
+
+impl<W> Iterator for Messages<'_, W> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.kcp.recv_message().ok()
+    }
+}
+
+// 连接状态，某个 segment 的发送次数达到 dead_link 之后连接被认为已断开
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Alive,
+    Dead,
+}
 
 #[repr(C)]
-pub struct Kcp<W: Write> {
+pub struct Kcp<W> {
     //标识这个会话ID
     conv: u32,
 
@@ -100,9 +127,6 @@ pub struct Kcp<W: Write> {
     //待接收消息序号。为了保证包的顺序，接收方会维护一个接收窗口，接收窗口有一个起始序号rcv_nxt（待接收消息序号）以及尾序号 rcv_nxt + rcv_wnd（接收窗口大小）
     rcv_nxt: u32,
 
-    // 拥塞窗口阈值，以包为单位（TCP以字节为单位）
-    ssthresh: u32,
-
     //RTT的变化量，代表连接的抖动情况
     rx_rttval: u32,
 
@@ -124,9 +148,6 @@ pub struct Kcp<W: Write> {
     //远端接收窗口大小
     rmt_wnd: u32,
 
-    //拥塞窗口大小
-    cwnd: u32,
-
     //探查变量，IKCP_ASK_TELL表示告知远端窗口大小。IKCP_ASK_SEND表示请求远端告知窗口大小
     probe: u32,
 
@@ -153,9 +174,6 @@ pub struct Kcp<W: Write> {
     //探查窗口需要等待的时间
     probe_wait: u32,
 
-    //可发送的最大数据量
-    incr: u32,
-
     // snd_queue --> snd_buf
     snd_queue: VecDeque<Segment>,
     rcv_queue: VecDeque<Segment>,
@@ -170,28 +188,47 @@ pub struct Kcp<W: Write> {
     // 存储消息字节流；
     buffer: BytesMut,
 
+    // 等待写入 output 的报文
+    output_queue: VecDeque<Bytes>,
+
     //触发快速重传的重复ACK个数；
     fastresend: u32,
 
     // 取消拥塞控制；
     nocwnd: bool,
 
+    // 对应 C 版编译时定义 IKCP_FASTACK_CONSERVE，只按发送时间更晚的 ack 计算快速重传，默认关闭
+    fastack_conserve: bool,
+
+    // 拥塞控制算法，默认为 KcpCongestion
+    congestion: Box<dyn CongestionController>,
+
+    // 拥塞控制给出发送速率时，当前还可以发送的字节数以及上次计算的时间
+    pacing_budget: u64,
+    ts_pacing: u32,
+
     //是否采用流传输模式；
     stream: bool,
 
+    // 单个 segment 最大发送次数，超过后认为连接断开
+    dead_link: u32,
+
+    state: LinkState,
+
+    stats: KcpStats,
+
     output: W,
 }
 
-impl<W: Write> Kcp<W> {
+impl<W> Kcp<W> {
     pub fn ickp_create(w: W, conv: u32) -> Self {
         Self {
-            conv: conv,
+            conv,
             mtu: IKCP_MTU_DEF,
             mss: IKCP_MTU_DEF - IKCP_OVERHEAD,
             snd_una: 0,
             snd_nxt: 0,
             rcv_nxt: 0,
-            ssthresh: IKCP_THRESH_INIT,
             rx_rttval: 0,
             rx_srtt: 0,
             rx_rto: IKCP_RTO_DEF,
@@ -199,7 +236,6 @@ impl<W: Write> Kcp<W> {
             snd_wnd: IKCP_WND_SND,
             rcv_wnd: IKCP_WND_RCV,
             rmt_wnd: IKCP_WND_RCV,
-            cwnd: 0,
             probe: 0,
             current: 0,
             interval: IKCP_INTERVAL,
@@ -209,62 +245,143 @@ impl<W: Write> Kcp<W> {
             updated: false,
             ts_probe: 0,
             probe_wait: 0,
-            incr: 0,
             snd_queue: VecDeque::new(),
             rcv_queue: VecDeque::new(),
             snd_buf: VecDeque::new(),
             rcv_buf: VecDeque::new(),
             acklist: Vec::new(),
             buffer: BytesMut::with_capacity((IKCP_MTU_DEF as usize + IKCP_OVERHEAD as usize) * 3),
+            output_queue: VecDeque::new(),
             fastresend: 0,
             nocwnd: false,
+            fastack_conserve: false,
+            congestion: Box::new(KcpCongestion::new()),
+            pacing_budget: 0,
+            ts_pacing: 0,
             stream: false,
+            dead_link: IKCP_DEADLINK,
+            state: LinkState::Alive,
+            stats: KcpStats::default(),
             output: w,
         }
     }
 
-    // user/upper level recv: returns size, returns below zero for EAGAIN
-    pub fn ikcp_recv(&mut self, buf: &mut [u8]) -> Result<usize, i32> {
+    // 按照校验过的配置创建 kcp
+    pub fn with_config(w: W, conv: u32, config: &KcpConfig) -> Result<Self, KcpError> {
+        config.validate()?;
+
+        let mut kcp = Self::ickp_create(w, conv);
+        kcp.ikcp_setmtu(config.mtu)?;
+        kcp.ikcp_wndsize(config.snd_wnd, config.rcv_wnd);
+        kcp.ikcp_nodelay(
+            config.nodelay,
+            config.interval,
+            config.fast_resend,
+            config.no_cwnd,
+        );
+        kcp.ikcp_deadlink(config.dead_link);
+        kcp.fastack_conserve = config.fastack_conserve;
+        kcp.congestion = config.congestion.controller();
+        kcp.stream = config.stream;
+        Ok(kcp)
+    }
+
+    // 创建流模式的 kcp，发送的数据会被合并到 mss 大小的 segment 中，接收时不再保留消息边界
+    pub fn ickp_create_stream(w: W, conv: u32) -> Self {
+        let mut kcp = Self::ickp_create(w, conv);
+        kcp.stream = true;
+        kcp
+    }
+
+    // user/upper level recv: returns size, returns KcpError::Empty for EAGAIN
+    pub fn ikcp_recv(&mut self, buf: &mut [u8]) -> Result<usize, KcpError> {
+        if self.state == LinkState::Dead {
+            return Err(KcpError::DeadLink);
+        }
         if self.rcv_queue.is_empty() {
-            return Err(-1);
+            return Err(KcpError::Empty);
         }
-        let peeksize = match self.ikcp_peeksize() {
-            Ok(x) => x,
-            Err(_) => return Err(-1),
-        };
+        let peeksize = self.ikcp_peeksize()? as usize;
+
+        if peeksize > buf.len() {
+            return Err(KcpError::BufferTooSmall { needed: peeksize });
+        }
+
+        // merge fragment
+        let mut n = 0;
+        self.ikcp_pop_message(|data| {
+            buf[n..n + data.len()].copy_from_slice(&data);
+            n += data.len();
+        });
+
+        assert!(n == peeksize);
+        Ok(n)
+    }
 
-        if peeksize as usize > buf.len() {
-            return Err(-1);
+    // 取出下一条完整的消息，只有一个分片时直接返回 segment 的负载，不复制数据；
+    // 连接已断开或者没有完整的消息时返回 None
+    pub fn recv_bytes(&mut self) -> Option<Bytes> {
+        if self.state == LinkState::Dead {
+            return None;
         }
+        let peeksize = self.ikcp_peeksize().ok()? as usize;
 
+        if self.rcv_queue[0].frg == 0 {
+            let mut message = Bytes::new();
+            self.ikcp_pop_message(|data| message = data);
+            return Some(message);
+        }
+        let mut message = BytesMut::with_capacity(peeksize);
+        self.ikcp_pop_message(|data| message.extend_from_slice(&data));
+        Some(message.freeze())
+    }
+
+    // 按 ikcp_peeksize 分配空间，把下一条消息追加到 buf 的末尾，返回消息的长度
+    pub fn recv_into(&mut self, buf: &mut Vec<u8>) -> Result<usize, KcpError> {
+        if self.state == LinkState::Dead {
+            return Err(KcpError::DeadLink);
+        }
+        if self.rcv_queue.is_empty() {
+            return Err(KcpError::Empty);
+        }
+        let peeksize = self.ikcp_peeksize()? as usize;
+
+        buf.reserve(peeksize);
+        self.ikcp_pop_message(|data| buf.extend_from_slice(&data));
+        Ok(peeksize)
+    }
+
+    // 取出下一条消息，不需要事先知道消息的大小
+    pub fn recv_message(&mut self) -> Result<Vec<u8>, KcpError> {
+        let mut buf = Vec::new();
+        self.recv_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    // 依次取出所有完整的消息，没有完整的消息或者连接断开时结束
+    pub fn messages(&mut self) -> Messages<'_, W> {
+        Messages { kcp: self }
+    }
+
+    // 从 rcv_queue 中依次取出一条消息的所有分片交给 f，调用前需要确认 ikcp_peeksize 成功
+    fn ikcp_pop_message(&mut self, mut f: impl FnMut(Bytes)) {
         let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
 
-        // merge fragment
-        let mut buf = Cursor::new(buf);
-        let mut index: usize = 0;
-        for seg in &self.rcv_queue {
-            if buf.write_all(&seg.data).is_err() {
-                return Err(-1);
-            }
-            index += 1;
-            if seg.frg == 0 {
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            let frg = seg.frg;
+            f(seg.data);
+            if frg == 0 {
                 break;
             }
         }
-        if index > 0 {
-            let new_rcv_queue = self.rcv_queue.split_off(index);
-            self.rcv_queue = new_rcv_queue;
-        }
-
-        assert!(buf.position() as usize == peeksize as usize);
 
         // move available data from rcv_buf -> rcv_queue
-        index = 0;
+        let mut index = 0;
         let mut nrcv_que = self.rcv_queue.len();
         for seg in &self.rcv_buf {
             if seg.sn == self.rcv_nxt && nrcv_que < self.rcv_wnd as usize {
                 nrcv_que += 1;
-                self.rcv_nxt += 1;
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
                 index += 1;
             } else {
                 break;
@@ -285,201 +402,234 @@ impl<W: Write> Kcp<W> {
             // tell remote my window size
             self.probe |= IKCP_ASK_TELL;
         }
-        Ok(buf.position() as usize)
     }
 
-    // user/upper level send, returns below zero for error
-    pub fn ikcp_send(&mut self, buf: &[u8]) -> Result<usize, i32> {
-        let n = buf.len();
+    // user/upper level send, returns the number of bytes queued
+    pub fn ikcp_send(&mut self, buf: &[u8]) -> Result<usize, KcpError> {
+        self.send_bytes(Bytes::copy_from_slice(buf))
+    }
+
+    // 与 ikcp_send 相同，但各个 segment 直接引用 data 的切片，不复制数据
+    pub fn send_bytes(&mut self, mut data: Bytes) -> Result<usize, KcpError> {
+        if self.state == LinkState::Dead {
+            return Err(KcpError::DeadLink);
+        }
+        let n = data.len();
         if n == 0 {
-            return Err(-1);
+            return Err(KcpError::EmptyPayload);
         }
-        let mut buf = Cursor::new(buf);
 
         // 1. 如果当前的 KCP 开启流模式，取出 `snd_queue` 中的最后一个报文将其填充到 mss 的长度，并设置其 frg 为 0.
         if self.stream {
             if let Some(seg) = self.snd_queue.back_mut() {
                 let l = seg.data.len();
                 if l < self.mss as usize {
-                    let new_len = min(l + n, self.mss as usize);
-                    seg.data.resize(new_len, 0);
-                    if buf.read_exact(&mut seg.data[l..new_len]).is_err() {
-                        return Err(-1);
-                    };
+                    let extend = min(self.mss as usize - l, data.len());
+                    let mut merged = BytesMut::with_capacity(l + extend);
+                    merged.extend_from_slice(&seg.data);
+                    merged.extend_from_slice(&data.split_to(extend));
+                    seg.data = merged.freeze();
+                    seg.len = seg.data.len() as u32;
                     seg.frg = 0;
-                    if buf.remaining() == 0 {
-                        return Ok(1);
+                    if data.is_empty() {
+                        return Ok(n);
                     }
                 }
             };
         }
 
         // 2. 计算剩下的数据需要分成几段
-        let count = if buf.remaining() <= self.mss as usize {
+        let count = if data.len() <= self.mss as usize {
             1
         } else {
-            (buf.remaining() + self.mss as usize - 1) / self.mss as usize
+            data.len().div_ceil(self.mss as usize)
         };
 
-        if count > 255 {
-            return Err(-1);
+        // 与 C 版一样分片数必须小于 IKCP_WND_RCV，否则对端的接收窗口放不下整条消息，永远无法接收；
+        // 流模式下每个 segment 单独接收，不受分片数限制
+        if !self.stream && count >= IKCP_WND_RCV as usize {
+            return Err(KcpError::TooManyFragments);
         }
         assert!(count > 0);
-        let count = count as u8;
-
 
         // 3. 为剩下的数据创建 KCP segment
         for i in 0..count {
-            let size = min(self.mss as usize, buf.remaining());
-            let mut seg = Segment::default();
-            //fix bug
-            seg.len = size as u32;
-            seg.data.resize(size, 0);
-            if buf.read_exact(&mut seg.data).is_err() {
-                return Err(-1);
+            let size = min(self.mss as usize, data.len());
+            let seg = Segment {
+                //fix bug
+                len: size as u32,
+                data: data.split_to(size),
+                // 流模式情况下分片编号不用填写
+                frg: if !self.stream {
+                    (count - i - 1) as u8
+                } else {
+                    0
+                },
+                ..Default::default()
             };
-
-            // 流模式情况下分片编号不用填写
-            seg.frg = if !self.stream { count - i - 1 } else { 0 };
             self.snd_queue.push_back(seg);
         }
-        Ok(n - buf.remaining())
+        Ok(n)
     }
 
     // update state (call it repeatedly, every 10ms-100ms), or you can ask
     // ikcp_check when to call it again (without ikcp_input/_send calling).
     // 'current' - current timestamp in millisec.
-    pub fn ikcp_input(&mut self, buf: &[u8]) -> Result<usize, i32> {
-        let n = buf.len();
-        let mut buf = Cursor::new(buf);
+    pub fn ikcp_input(&mut self, buf: &[u8]) -> Result<usize, KcpError> {
+        self.stats.in_pkts += 1;
+        self.stats.in_bytes += buf.len() as u64;
+        let result = self.ikcp_input_segments(buf, Bytes::copy_from_slice);
+        if result.is_err() {
+            self.stats.input_errors += 1;
+        }
+        result
+    }
 
-        if buf.remaining() < IKCP_OVERHEAD as usize {
-            return Err(-1);
+    // 与 ikcp_input 相同，但收到的数据直接引用 buf 的切片，不复制数据
+    pub fn input_bytes(&mut self, buf: Bytes) -> Result<usize, KcpError> {
+        self.stats.in_pkts += 1;
+        self.stats.in_bytes += buf.len() as u64;
+        let result = self.ikcp_input_segments(&buf, |payload| buf.slice_ref(payload));
+        if result.is_err() {
+            self.stats.input_errors += 1;
         }
+        result
+    }
+
+    // to_bytes 把报文中的负载转换成 segment 保存的数据
+    fn ikcp_input_segments(
+        &mut self,
+        data: &[u8],
+        to_bytes: impl Fn(&[u8]) -> Bytes,
+    ) -> Result<usize, KcpError> {
         let old_una = self.snd_una;
+        let mut acked = (0, 0);
+        let mut rtt_sample = None;
         let mut flag = false;
         //记录当前收到的最大的 ACK 编号，在快重传的过程计算已发送的数据包被跳过的次数；
         let mut maxack: u32 = 0;
-
-        while buf.remaining() >= IKCP_OVERHEAD as usize {
-            let conv = buf.get_u32_le();
+        let mut latest_ts: u32 = 0;
+
+        let mut segments = Segments::new(data);
+        for seg in segments.by_ref() {
+            let SegmentRef {
+                header:
+                    SegmentHeader {
+                        conv,
+                        cmd,
+                        frg,
+                        wnd,
+                        ts,
+                        sn,
+                        una,
+                        len,
+                    },
+                data: payload,
+            } = seg?;
 
             if conv != self.conv {
-                return Err(-1);
-            }
-
-            let cmd = buf.get_u8();
-            let frg = buf.get_u8();
-            let wnd = buf.get_u16_le();
-            let ts = buf.get_u32_le();
-            let sn = buf.get_u32_le();
-            let una = buf.get_u32_le();
-            let len = buf.get_u32_le();
-
-            let len = len as usize;
-            if buf.remaining() < len {
-                return Err(-1);
-            }
-
-            if cmd != IKCP_CMD_PUSH
-                && cmd != IKCP_CMD_ACK
-                && cmd != IKCP_CMD_WASK
-                && cmd != IKCP_CMD_WINS
-            {
-                return Err(-1);
+                return Err(KcpError::ConvMismatch {
+                    expected: self.conv,
+                    got: conv,
+                });
             }
+            self.stats.in_segs += 1;
 
             self.rmt_wnd = wnd as u32;
-            self.ikcp_parse_una(una);
+            self.ikcp_parse_una(una, &mut acked);
             self.ikcp_shrink_buf();
             if cmd == IKCP_CMD_ACK {
+                self.stats.acks_received += 1;
                 let rtt = diff(self.current, ts);
                 if rtt >= 0 {
                     self.ikcp_update_ack(rtt as u32);
+                    rtt_sample = Some(rtt as u32);
                 }
-                self.ikcp_parse_ack(sn);
+                self.ikcp_parse_ack(sn, &mut acked);
                 self.ikcp_shrink_buf();
+                // 开启 fastack_conserve 时与 C 版的 IKCP_FASTACK_CONSERVE 一致，只采用发送时间更晚的 ack
                 if !flag {
                     flag = true;
                     maxack = sn;
-                } else {
-                    if sn > maxack {
-                        maxack = sn;
-                    }
+                    latest_ts = ts;
+                } else if diff(sn, maxack) > 0
+                    && (!self.fastack_conserve || diff(ts, latest_ts) > 0)
+                {
+                    maxack = sn;
+                    latest_ts = ts;
                 }
-           
-           
             } else if cmd == IKCP_CMD_PUSH {
+                self.stats.in_data_segs += 1;
+                self.stats.in_data_bytes += payload.len() as u64;
                 //1. 对于来自于对方的标准数据包，首先需要检测该报文的编号 sn 是否在窗口范围内；
-                if sn < self.rcv_nxt + self.rcv_wnd {
+                if diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 {
                     //2. 调用 ikcp_ack_push 将对该报文的确认 ACK 报文放入 ACK 列表中，ACK 列表的组织方式在前文中已经介绍；
                     self.acklist.push((sn, ts));
-                    if sn >= self.rcv_nxt {
-                        let mut seg = Segment::default();
-                        seg.conv = conv;
-                        seg.cmd = cmd;
-                        seg.frg = frg;
-                        seg.wnd = wnd;
-                        seg.ts = ts;
-                        seg.sn = sn;
-                        seg.una = una;
-                        //fix bug
-                        seg.len = len as u32;
-                        seg.data.resize(len, 0);
-                        if buf.read_exact(&mut seg.data).is_err() {
-                            return Err(-2);
-                        }
+                    if diff(sn, self.rcv_nxt) >= 0 {
+                        let seg = Segment {
+                            conv,
+                            cmd,
+                            frg,
+                            wnd,
+                            ts,
+                            sn,
+                            una,
+                            len,
+                            data: to_bytes(payload),
+                            ..Default::default()
+                        };
                         //3. 最后调用 ikcp_parse_data 将该报文插入到 rcv_buf 链表中；
                         self.ikcp_parse_data(seg);
+                    } else {
+                        self.stats.repeat_segs += 1;
                     }
+                } else {
+                    self.stats.out_of_window_segs += 1;
                 }
             } else if cmd == IKCP_CMD_WASK {
+                self.stats.wask_received += 1;
                 //对于接收到的 IKCP_CMD_WASK 报文，直接标记下次将发送窗口通知报文
                 self.probe |= IKCP_ASK_TELL;
             } else if cmd == IKCP_CMD_WINS {
+                self.stats.wins_received += 1;
                 //而对于报文 IKCP_CMD_WINS 无需做任何特殊操作;
             } else {
-                return Err(-1);
+                return Err(KcpError::UnknownCommand(cmd));
             }
         }
         if flag {
             // 根据记录的最大的 ACK 编号 maxack 来更新 snd_buf 中的报文的 fastack，
             // 这个过程在介绍 ikcp_flush 中提到过，对于 fastack 大于设置的 resend 参数时，将立马进行快重传；
-            self.ikcp_parse_fastack(maxack);
+            self.ikcp_parse_fastack(maxack, latest_ts);
         }
 
-        //最后，根据接收到报文的 una 和 KCP 控制块的 una 参数进行流控
-        if self.snd_una > old_una {
-            if self.cwnd < self.rmt_wnd {
-                let mss = self.mss as u32;
-                if self.cwnd < self.ssthresh {
-                    self.cwnd += 1;
-                    self.incr += mss;
-                } else {
-                    if self.incr < mss {
-                        self.incr = mss;
-                    }
-                    self.incr += (mss * mss) / self.incr + (mss / 16);
-                    if (self.cwnd + 1) * mss <= self.incr {
-                        self.cwnd += 1;
-                    }
-                }
-                if self.cwnd > self.rmt_wnd {
-                    self.cwnd = self.rmt_wnd;
-                    self.incr = self.rmt_wnd * mss;
-                }
-            }
+        //最后，把确认信息交给拥塞控制算法
+        if acked.0 > 0 || rtt_sample.is_some() {
+            self.congestion.on_ack(&AckEvent {
+                current: self.current,
+                una_advanced: diff(self.snd_una, old_una) > 0,
+                acked_segs: acked.0,
+                acked_bytes: acked.1,
+                rtt: rtt_sample,
+                srtt: self.rx_srtt,
+                inflight: self.snd_buf.len() as u32,
+                mss: self.mss,
+                rmt_wnd: self.rmt_wnd,
+            });
         }
-        Ok(n - buf.remaining())
+        Ok(segments.offset())
     }
 
     //当接收到 una 信息后，表明 sn 小于 una 的数据包都已经被对方接收到，
     //因此可以直接从 snd_buf 中删除。同时调用 ikcp_shrink_buf 来更新 KCP 控制块的 snd_una 数值。
-    fn ikcp_parse_una(&mut self, una: u32) {
+    // acked 累计被确认的 segment 数和负载字节数
+    fn ikcp_parse_una(&mut self, una: u32, acked: &mut (u32, u64)) {
         let mut index: usize = 0;
         for seg in &self.snd_buf {
-            if una > seg.sn {
+            if diff(una, seg.sn) > 0 {
                 index += 1;
+                acked.0 += 1;
+                acked.1 += seg.data.len() as u64;
             } else {
                 break;
             }
@@ -490,14 +640,15 @@ impl<W: Write> Kcp<W> {
         }
     }
 
-    fn ikcp_parse_fastack(&mut self, sn: u32) {
-        if sn < self.snd_una || sn >= self.snd_nxt {
+    // ts 为 sn 对应的 ack 回显的发送时间，开启 fastack_conserve 时只有在它之前发送的 segment 才算被跳过
+    fn ikcp_parse_fastack(&mut self, sn: u32, ts: u32) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
             return;
         }
         for seg in &mut self.snd_buf {
-            if sn < seg.sn {
+            if diff(sn, seg.sn) < 0 {
                 break;
-            } else if sn != seg.sn {
+            } else if sn != seg.sn && (!self.fastack_conserve || diff(ts, seg.ts) >= 0) {
                 seg.fastack += 1;
             }
         }
@@ -506,15 +657,18 @@ impl<W: Write> Kcp<W> {
     //之后调用函数 ikcp_parse_ack 来根据 ACK 的编号确认对方收到了哪个数据包；
     //注意KCP 中同时使用了 UNA 以及 ACK 编号的报文确认手段。
     //UNA 表示此前所有的数据都已经被接收到，而 ACK 表示指定编号的数据包被接收到；
-    fn ikcp_parse_ack(&mut self, sn: u32) {
-        if sn < self.snd_una || sn >= self.snd_nxt {
+    fn ikcp_parse_ack(&mut self, sn: u32, acked: &mut (u32, u64)) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
             return;
         }
         for i in 0..self.snd_buf.len() {
             if sn == self.snd_buf[i].sn {
-                self.snd_buf.remove(i);
+                if let Some(seg) = self.snd_buf.remove(i) {
+                    acked.0 += 1;
+                    acked.1 += seg.data.len() as u64;
+                }
                 break;
-            } else if sn < self.snd_buf[i].sn {
+            } else if diff(sn, self.snd_buf[i].sn) < 0 {
                 break;
             }
         }
@@ -522,7 +676,7 @@ impl<W: Write> Kcp<W> {
 
     fn ikcp_parse_data(&mut self, newseg: Segment) {
         let sn = newseg.sn;
-        if sn >= self.rcv_nxt + self.rcv_wnd || sn < self.rcv_nxt {
+        if diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0 || diff(sn, self.rcv_nxt) < 0 {
             // ikcp_segment_delete(kcp, newseg);
             return;
         }
@@ -533,7 +687,7 @@ impl<W: Write> Kcp<W> {
             if sn == seg.sn {
                 repeat = true;
                 break;
-            } else if sn > seg.sn {
+            } else if diff(sn, seg.sn) > 0 {
                 break;
             }
             index -= 1;
@@ -543,6 +697,7 @@ impl<W: Write> Kcp<W> {
             self.rcv_buf.insert(index, newseg);
         } else {
             // ikcp_segment_delete(kcp, newseg);
+            self.stats.repeat_segs += 1;
         }
 
         // move available data from rcv_buf -> rcv_queue
@@ -551,7 +706,7 @@ impl<W: Write> Kcp<W> {
         for seg in &self.rcv_buf {
             if seg.sn == self.rcv_nxt && nrcv_que < self.rcv_wnd as usize {
                 nrcv_que += 1;
-                self.rcv_nxt += 1;
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
                 index += 1;
             } else {
                 break;
@@ -564,12 +719,8 @@ impl<W: Write> Kcp<W> {
         }
     }
 
-    //---------------------------------------------------------------------
-    // update state (call it repeatedly, every 10ms-100ms), or you can ask
-    // ikcp_check when to call it again (without ikcp_input/_send calling).
-    // 'current' - current timestamp in millisec.
-    //---------------------------------------------------------------------
-    pub fn ikcp_update(&mut self, current: u32) {
+    // 推进时钟，返回是否到了 flush 的时间
+    fn ikcp_tick(&mut self, current: u32) -> bool {
         self.current = current;
 
         if !self.updated {
@@ -579,18 +730,19 @@ impl<W: Write> Kcp<W> {
 
         let mut slap = diff(self.current, self.ts_flush);
 
-        if slap > 10000 || slap < -10000 {
+        if !(-10000..10000).contains(&slap) {
             self.ts_flush = self.current;
             slap = 0;
         }
 
         if slap >= 0 {
-            self.ts_flush += self.interval;
+            self.ts_flush = self.ts_flush.wrapping_add(self.interval);
             if diff(self.current, self.ts_flush) >= 0 {
-                self.ts_flush = self.current + self.interval;
+                self.ts_flush = self.current.wrapping_add(self.interval);
             }
-            self.ikcp_flush();
+            return true;
         }
+        false
     }
 
     fn ikcp_shrink_buf(&mut self) {
@@ -600,22 +752,23 @@ impl<W: Write> Kcp<W> {
         }
     }
 
-    pub fn ikcp_peeksize(&self) -> Result<u32, i32> {
+    pub fn ikcp_peeksize(&self) -> Result<u32, KcpError> {
         let seg = match self.rcv_queue.front() {
             Some(x) => x,
-            None => return Err(-1),
+            None => return Err(KcpError::Empty),
         };
 
         if seg.frg == 0 {
             return Ok(seg.len);
         }
 
-        if self.rcv_queue.len() < (seg.frg + 1) as usize {
-            return Err(-1);
+        // frg 来自网络，可能是 255
+        if self.rcv_queue.len() < seg.frg as usize + 1 {
+            return Err(KcpError::Empty);
         }
 
         let mut length = 0;
-        for seg in (&self.rcv_queue).into_iter() {
+        for seg in &self.rcv_queue {
             length += seg.len;
             if seg.frg == 0 {
                 break;
@@ -631,46 +784,42 @@ impl<W: Write> Kcp<W> {
             self.rx_srtt = rtt;
             self.rx_rttval = rtt / 2;
         } else {
-            let delta = if rtt > self.rx_srtt {
-                rtt - self.rx_srtt
-            } else {
-                self.rx_srtt - rtt
-            };
+            let delta = rtt.abs_diff(self.rx_srtt);
 
-            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
-            self.rx_srtt = (7 * self.rx_srtt + rtt) / 8;
+            // rtt 来自对端回显的 ts，可能接近 i32::MAX，用 u64 计算避免溢出
+            self.rx_rttval = ((3 * self.rx_rttval as u64 + delta as u64) / 4) as u32;
+            self.rx_srtt = ((7 * self.rx_srtt as u64 + rtt as u64) / 8) as u32;
             if self.rx_srtt < 1 {
                 self.rx_srtt = 1;
             }
         }
 
-        let rto = self.rx_srtt + max(self.interval, 4 * self.rx_rttval);
+        let rto = self
+            .rx_srtt
+            .saturating_add(max(self.interval, self.rx_rttval.saturating_mul(4)));
         self.rx_rto = ibound(self.rx_minrto, rto, IKCP_RTO_MAX);
     }
 
-    // ikcp_flush
-    pub fn ikcp_flush(&mut self) {
+    // 生成待发送的报文，放入 output_queue
+    fn ikcp_flush_segments(&mut self) {
         // 'ikcp_update' haven't been called.
         if !self.updated {
             return;
         }
 
-        let mut seg = Segment::default();
-        seg.conv = self.conv;
-        seg.cmd = IKCP_CMD_ACK;
-        seg.wnd = self.ikcp_wnd_unused();
-        seg.una = self.rcv_nxt;
+        let mut seg = Segment {
+            conv: self.conv,
+            cmd: IKCP_CMD_ACK,
+            wnd: self.ikcp_wnd_unused(),
+            una: self.rcv_nxt,
+            ..Default::default()
+        };
 
         // 发送确认ACK 包
-        for ack in &self.acklist {
-            if (self.buffer.capacity() - self.buffer.len()) + IKCP_OVERHEAD as usize
-                > self.mtu as usize
-            {
-                self.output.write_all(&self.buffer).unwrap();
-                self.buffer.clear();
-            }
-            seg.sn = ack.0;
-            seg.ts = ack.1;
+        for i in 0..self.acklist.len() {
+            self.ikcp_make_space(IKCP_OVERHEAD as usize);
+            (seg.sn, seg.ts) = self.acklist[i];
+            self.stats.acks_sent += 1;
 
             seg.encode(&mut self.buffer);
         }
@@ -680,7 +829,7 @@ impl<W: Write> Kcp<W> {
         if self.rmt_wnd == 0 {
             if self.probe_wait == 0 {
                 self.probe_wait = IKCP_PROBE_INIT;
-                self.ts_probe = self.current + self.probe_wait;
+                self.ts_probe = self.current.wrapping_add(self.probe_wait);
             } else {
                 if diff(self.current, self.ts_probe) >= 0 {
                     if self.probe_wait < IKCP_PROBE_INIT {
@@ -690,7 +839,7 @@ impl<W: Write> Kcp<W> {
                     if self.probe_wait > IKCP_PROBE_LIMIT {
                         self.probe_wait = IKCP_PROBE_LIMIT;
                     }
-                    self.ts_probe = self.current + self.probe_wait;
+                    self.ts_probe = self.current.wrapping_add(self.probe_wait);
                     self.probe |= IKCP_ASK_SEND
                 }
             }
@@ -702,24 +851,16 @@ impl<W: Write> Kcp<W> {
         // flush window probing commands
         if (self.probe & IKCP_ASK_SEND) != 0 {
             seg.cmd = IKCP_CMD_WASK;
-            if (self.buffer.capacity() - self.buffer.len()) + IKCP_OVERHEAD as usize
-                > self.mtu as usize
-            {
-                self.output.write_all(&self.buffer).unwrap();
-                self.buffer.clear();
-            }
+            self.stats.wask_sent += 1;
+            self.ikcp_make_space(IKCP_OVERHEAD as usize);
             seg.encode(&mut self.buffer);
         }
 
         // flush window probing commands
         if (self.probe & IKCP_ASK_TELL) != 0 {
             seg.cmd = IKCP_CMD_WINS;
-            if (self.buffer.capacity() - self.buffer.len()) + IKCP_OVERHEAD as usize
-                > self.mtu as usize
-            {
-                self.output.write_all(&self.buffer).unwrap();
-                self.buffer.clear();
-            }
+            self.stats.wins_sent += 1;
+            self.ikcp_make_space(IKCP_OVERHEAD as usize);
             seg.encode(&mut self.buffer);
         }
 
@@ -728,18 +869,40 @@ impl<W: Write> Kcp<W> {
         // 设置nocwnd cwnd 只会由发送窗口和对端接收端口决定
         let mut cwnd = min(self.snd_wnd, self.rmt_wnd);
         if !self.nocwnd {
-            cwnd = min(self.cwnd, cwnd);
+            cwnd = min(self.congestion.window(), cwnd);
         }
 
+        // 按照拥塞控制给出的速率累计可以发送的字节数，最多积累两个 interval
+        let pacing = self.congestion.pacing_rate();
+        if let Some(rate) = pacing {
+            let elapsed = max(diff(self.current, self.ts_pacing), 0) as u64;
+            let burst = max(
+                rate * self.interval as u64 * 2 / 1000,
+                2 * (self.mss + IKCP_OVERHEAD) as u64,
+            );
+            self.pacing_budget = min(self.pacing_budget + rate * elapsed / 1000, burst);
+        }
+        self.ts_pacing = self.current;
+
         // move data from snd_queue to snd_buf
-        while diff(self.snd_nxt, self.snd_una + cwnd) < 0 {
+        while diff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            if pacing.is_some() {
+                let need = match self.snd_queue.front() {
+                    Some(seg) => (seg.data.len() + IKCP_OVERHEAD as usize) as u64,
+                    None => break,
+                };
+                if self.pacing_budget < need {
+                    break;
+                }
+                self.pacing_budget -= need;
+            }
             if let Some(mut newseg) = self.snd_queue.pop_front() {
                 newseg.conv = self.conv;
                 newseg.cmd = IKCP_CMD_PUSH;
                 newseg.wnd = seg.wnd;
                 newseg.ts = self.current;
                 newseg.sn = self.snd_nxt;
-                self.snd_nxt += 1;
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
                 newseg.una = self.rcv_nxt;
                 newseg.resendts = self.current;
                 newseg.rto = self.rx_rto;
@@ -759,7 +922,7 @@ impl<W: Write> Kcp<W> {
             u32::MAX
         };
 
-        // 是否开启了 nodelay 
+        // 是否开启了 nodelay
         let rtomin = if !self.nodelay { self.rx_rto >> 3 } else { 0 };
 
         let mut lost = false;
@@ -768,88 +931,92 @@ impl<W: Write> Kcp<W> {
         for segment in &mut self.snd_buf {
             let mut needsend = false;
 
-             // 1. 如果该报文是第一次传输，那么直接发送
+            // 1. 如果该报文是第一次传输，那么直接发送
             if segment.xmit == 0 {
                 needsend = true;
                 segment.xmit += 1;
                 segment.rto = self.rx_rto;
-                segment.resendts = self.current + segment.rto + rtomin;
+                segment.resendts = self.current.wrapping_add(segment.rto + rtomin);
 
             // 2. 如果已经到了该报文的重传时间，那么发送该报文
             } else if diff(self.current, segment.resendts) >= 0 {
                 needsend = true;
                 segment.xmit += 1;
                 self.xmit += 1;
+                // 与 C 版一致：普通模式下 rto 翻倍，nodelay 模式下增加 1.5 倍
                 if !self.nodelay {
-                    segment.rto += self.rx_rto;
+                    segment.rto += max(segment.rto, self.rx_rto);
                 } else {
-                    segment.rto += self.rx_rto / 2;
+                    segment.rto += segment.rto / 2;
                 }
-                segment.resendts = self.current + segment.rto;
+                segment.resendts = self.current.wrapping_add(segment.rto);
 
                 // 标识重传
                 lost = true;
+                self.stats.lost_segs += 1;
 
-             // 3. 如果该报文被跳过的次数超过了设置的快重传次数，发送该报文
-            } else if segment.fastack >= resent {
+                // 3. 如果该报文被跳过的次数超过了设置的快重传次数，发送该报文，
+                // 发送次数超过 IKCP_FASTACK_LIMIT 之后只等待超时重传
+            } else if segment.fastack >= resent && segment.xmit <= IKCP_FASTACK_LIMIT {
                 needsend = true;
                 segment.xmit += 1;
                 segment.fastack = 0;
-                segment.resendts = self.current + segment.rto;
+                segment.resendts = self.current.wrapping_add(segment.rto);
 
                 // 标识快重传发生
                 change = true;
+                self.stats.fast_retrans_segs += 1;
             }
 
             if needsend {
+                if segment.xmit >= self.dead_link {
+                    self.state = LinkState::Dead;
+                }
+
                 segment.ts = self.current;
                 segment.wnd = seg.wnd;
                 segment.una = self.rcv_nxt;
 
-                if ((self.buffer.capacity() - self.buffer.len())
-                    + IKCP_OVERHEAD as usize
-                    + segment.data.len() as usize)
-                    > self.mtu as usize
+                // 修改 mtu 之前分好的 segment 可能比 mtu 大，此时单独作为一个报文发送，
+                // 和 C 版一样不输出空报文
+                if !self.buffer.is_empty()
+                    && self.buffer.len() + IKCP_OVERHEAD as usize + segment.data.len()
+                        > self.mtu as usize
                 {
-                    self.output.write_all(&self.buffer).unwrap();
-                    self.buffer.clear();
+                    self.output_queue.push_back(self.buffer.split().freeze());
                 }
 
                 segment.encode(&mut self.buffer);
+                self.stats.out_data_segs += 1;
+                self.stats.out_data_bytes += segment.data.len() as u64;
             }
         }
 
         // flush remain segments
-        if self.buffer.len() > 0 {
-            self.output.write_all(&self.buffer).unwrap();
-            self.buffer.clear();
+        if !self.buffer.is_empty() {
+            self.output_queue.push_back(self.buffer.split().freeze());
         }
 
         // 快重传和丢包时的窗口更新算法不一致，这一点类似于 TCP 协议的拥塞控制和快恢复算法；
         // 根据change 更新窗口大小
         if change {
-            let inflight = self.snd_nxt - self.snd_una;
-            self.ssthresh = inflight / 2;
-            if self.ssthresh < IKCP_THRESH_MIN {
-                self.ssthresh = IKCP_THRESH_MIN;
-            }
-            self.cwnd = self.ssthresh + resent;
-            self.incr = self.cwnd * self.mss;
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.congestion
+                .on_fast_retransmit(inflight, resent, self.mss);
         }
 
         // 根据设置的 lost 更新窗口大小
         if lost {
-            self.ssthresh = cwnd / 2;
-            if self.ssthresh < IKCP_THRESH_MIN {
-                self.ssthresh = IKCP_THRESH_MIN;
-            }
-            self.cwnd = 1;
-            self.incr = self.mss;
+            self.congestion.on_timeout_loss(cwnd, self.mss);
         }
 
-        if self.cwnd < 1 {
-            self.cwnd = 1;
-            self.incr = self.mss;
+        self.congestion.on_flush(self.mss);
+    }
+
+    // 当前报文放不下 need 字节时，先把 buffer 中已有的数据作为一个报文排队
+    fn ikcp_make_space(&mut self, need: usize) {
+        if !self.buffer.is_empty() && self.buffer.len() + need > self.mtu as usize {
+            self.output_queue.push_back(self.buffer.split().freeze());
         }
     }
 
@@ -862,30 +1029,104 @@ impl<W: Write> Kcp<W> {
     // schedule ikcp_update (eg. implementing an epoll-like mechanism,
     // or optimize ikcp_update when handling massive kcp connections)
     //---------------------------------------------------------------------
-    pub fn ikcp_check(&mut self, _current: u32) -> i32 {
-        todo!()
+    pub fn ikcp_check(&self, current: u32) -> u32 {
+        if !self.updated {
+            return current;
+        }
+
+        let mut ts_flush = self.ts_flush;
+        let slap = diff(current, ts_flush);
+        if !(-10000..10000).contains(&slap) {
+            ts_flush = current;
+        }
+
+        if diff(current, ts_flush) >= 0 {
+            return current;
+        }
+
+        // 没有任何待处理的数据时，下次 flush 什么也不会做，无需按 interval 轮询
+        let mut deadline: Option<u32> = None;
+        let mut earliest = |ts: u32| {
+            // flush 只会发生在 ts_flush 之后
+            let ts = if diff(ts, ts_flush) < 0 { ts_flush } else { ts };
+            match deadline {
+                Some(d) if diff(d, ts) <= 0 => {}
+                _ => deadline = Some(ts),
+            }
+        };
+
+        // 待发送的 ack、窗口探测以及 snd_queue 中的数据都在下一次 flush 时发送
+        if !self.acklist.is_empty() || self.probe != 0 || !self.snd_queue.is_empty() {
+            earliest(ts_flush);
+        }
+
+        // 远端窗口为 0 时需要定时发送 IKCP_CMD_WASK
+        if self.rmt_wnd == 0 {
+            earliest(if self.probe_wait == 0 {
+                ts_flush
+            } else {
+                self.ts_probe
+            });
+        }
+
+        // snd_buf 中的报文在超时后需要重传
+        for seg in &self.snd_buf {
+            earliest(seg.resendts);
+        }
+
+        let minimal = match deadline {
+            Some(d) => diff(d, current) as u32,
+            None => IKCP_CHECK_IDLE,
+        };
+        current.wrapping_add(min(minimal, IKCP_CHECK_IDLE))
+    }
+
+    // sans-IO 接口：output 为 () 时使用，由调用者自己取走报文并发送
+    //
+    // let mut kcp = Kcp::ickp_create((), conv);
+    // kcp.handle_timeout(now);
+    // while let Some(pkt) = kcp.poll_transmit() { socket.send(&pkt)?; }
+    // let next = kcp.poll_timeout();
+    //
+    // 推进时钟，到了 flush 时间时把待发送的报文放入发送队列
+    pub fn handle_timeout(&mut self, now: u32) {
+        if self.ikcp_tick(now) {
+            self.ikcp_flush_segments();
+        }
+    }
+
+    // 下一次需要调用 handle_timeout 的时间戳，同 ikcp_check
+    pub fn poll_timeout(&self) -> u32 {
+        self.ikcp_check(self.current)
+    }
+
+    // 查看下一个待发送的报文但不取出，发送失败时报文留在队列中
+    pub fn peek_transmit(&self) -> Option<&Bytes> {
+        self.output_queue.front()
+    }
+
+    // 取出一个待发送的报文
+    pub fn poll_transmit(&mut self) -> Option<Bytes> {
+        let pkt = self.output_queue.pop_front()?;
+        self.stats.out_pkts += 1;
+        self.stats.out_bytes += pkt.len() as u64;
+        Some(pkt)
     }
 
     // change MTU size, default is 1400
-    pub fn ikcp_setmtu(&mut self, mtu: u32) -> Result<(), i32> {
+    pub fn ikcp_setmtu(&mut self, mtu: u32) -> Result<(), KcpError> {
         if mtu < 50 || mtu < IKCP_OVERHEAD {
-            return Err(-1);
+            return Err(KcpError::InvalidMtu(mtu));
         }
 
         self.mtu = mtu;
         self.mss = mtu - IKCP_OVERHEAD;
 
-        return Ok(());
+        Ok(())
     }
 
     pub fn ikcp_interval(&mut self, internal: u32) {
-        self.interval = if internal > 5000 {
-            5000
-        } else if internal < 10 {
-            10
-        } else {
-            internal
-        }
+        self.interval = internal.clamp(10, 5000);
     }
 
     // fastest: ikcp_nodelay(kcp, 1, 20, 2, 1)
@@ -904,13 +1145,7 @@ impl<W: Write> Kcp<W> {
             IKCP_RTO_MIN
         };
 
-        self.interval = if internal > 5000 {
-            5000
-        } else if internal < 10 {
-            10
-        } else {
-            internal
-        }
+        self.interval = internal.clamp(10, 5000);
     }
 
     //set maximum window size: sndwnd=32, rcvwnd=32 by default
@@ -928,6 +1163,145 @@ impl<W: Write> Kcp<W> {
         }
     }
 
+    // 开启或关闭 IKCP_FASTACK_CONSERVE 的快速重传规则
+    pub fn set_fastack_conserve(&mut self, conserve: bool) {
+        self.fastack_conserve = conserve;
+    }
+
+    // set how many times a segment may be sent before the link is dead, default is 20
+    pub fn ikcp_deadlink(&mut self, dead_link: u32) {
+        if dead_link > 0 {
+            self.dead_link = dead_link;
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    pub fn stats(&self) -> &KcpStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = KcpStats::default();
+    }
+
+    // 当前状态的快照，用于监控和上层的自适应逻辑
+    pub fn snapshot(&self) -> KcpState {
+        KcpState {
+            rx_srtt: self.rx_srtt,
+            rx_rttval: self.rx_rttval,
+            rx_rto: self.rx_rto,
+            cwnd: self.congestion.window(),
+            ssthresh: self.congestion.ssthresh(),
+            rmt_wnd: self.rmt_wnd,
+            snd_wnd: self.snd_wnd,
+            rcv_wnd: self.rcv_wnd,
+            snd_una: self.snd_una,
+            snd_nxt: self.snd_nxt,
+            rcv_nxt: self.rcv_nxt,
+            snd_queue: self.snd_queue.len(),
+            snd_buf: self.snd_buf.len(),
+            rcv_queue: self.rcv_queue.len(),
+            rcv_buf: self.rcv_buf.len(),
+            acks_pending: self.acklist.len(),
+            ts_probe: self.ts_probe,
+            probe_wait: self.probe_wait,
+            xmit: self.xmit,
+            link_state: self.state,
+        }
+    }
+
+    // 检查各个队列的不变量，违反时 panic，供测试和 fuzz 使用
+    #[cfg(any(test, fuzzing))]
+    pub fn check_invariants(&self) {
+        // snd_buf 按 sn 严格递增，都在 [snd_una, snd_nxt) 之内
+        match self.snd_buf.front() {
+            Some(seg) => assert_eq!(seg.sn, self.snd_una),
+            None => assert_eq!(self.snd_una, self.snd_nxt),
+        }
+        let mut next = self.snd_una;
+        for seg in &self.snd_buf {
+            assert!(diff(seg.sn, next) >= 0, "snd_buf is not sorted by sn");
+            assert!(diff(seg.sn, self.snd_nxt) < 0, "snd_buf sn beyond snd_nxt");
+            next = seg.sn.wrapping_add(1);
+        }
+
+        // rcv_buf 按 sn 严格递增，都在接收窗口之内
+        let mut next = self.rcv_nxt;
+        for seg in &self.rcv_buf {
+            assert!(diff(seg.sn, next) >= 0, "rcv_buf is not sorted by sn");
+            assert!(
+                diff(seg.sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0,
+                "rcv_buf sn beyond receive window"
+            );
+            next = seg.sn.wrapping_add(1);
+        }
+
+        // 接收端占用的内存受接收窗口限制
+        assert!(self.rcv_queue.len() <= self.rcv_wnd as usize);
+        assert!(self.rcv_buf.len() < self.rcv_wnd as usize);
+
+        let queues = [
+            &self.snd_queue,
+            &self.snd_buf,
+            &self.rcv_queue,
+            &self.rcv_buf,
+        ];
+        for seg in queues.into_iter().flatten() {
+            assert_eq!(seg.len as usize, seg.data.len());
+        }
+
+        // 报文不为空，超过 mtu 时只能是修改 mtu 之前分好的单个 segment
+        for pkt in &self.output_queue {
+            assert!(pkt.len() >= IKCP_OVERHEAD as usize);
+            if pkt.len() > self.mtu as usize {
+                let len = u32::from_le_bytes([pkt[20], pkt[21], pkt[22], pkt[23]]);
+                assert_eq!(pkt.len(), (IKCP_OVERHEAD + len) as usize);
+            }
+        }
+    }
+
+    // 替换拥塞控制算法，应该在发送数据之前调用
+    pub fn set_congestion_controller<C: CongestionController + 'static>(&mut self, cc: C) {
+        self.congestion = Box::new(cc);
+    }
+
+    pub fn congestion_controller(&self) -> &dyn CongestionController {
+        self.congestion.as_ref()
+    }
+
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.stream
+    }
+
+    // 最大分片大小
+    pub fn mss(&self) -> u32 {
+        self.mss
+    }
+
+    // 消息模式下一次 ikcp_send 最多可以发送的字节数，流模式下不受限制
+    pub fn max_message_size(&self) -> usize {
+        if self.stream {
+            usize::MAX
+        } else {
+            (IKCP_WND_RCV - 1) as usize * self.mss as usize
+        }
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
     // get how many packet is waiting to be sent
     pub fn ikcp_waitsnd(&self) -> usize {
         self.snd_buf.len() + self.snd_queue.len()
@@ -938,23 +1312,1166 @@ impl<W: Write> Kcp<W> {
         if self.rcv_queue.len() < self.rcv_wnd as usize {
             return (self.rcv_wnd as usize - self.rcv_queue.len()) as u16;
         }
-        return 0;
+        0
+    }
+}
+
+impl<W: Write> Kcp<W> {
+    //---------------------------------------------------------------------
+    // update state (call it repeatedly, every 10ms-100ms), or you can ask
+    // ikcp_check when to call it again (without ikcp_input/_send calling).
+    // 'current' - current timestamp in millisec.
+    //---------------------------------------------------------------------
+    pub fn ikcp_update(&mut self, current: u32) -> Result<(), KcpError> {
+        if self.ikcp_tick(current) {
+            return self.ikcp_flush();
+        }
+        Ok(())
+    }
+
+    // ikcp_flush
+    pub fn ikcp_flush(&mut self) -> Result<(), KcpError> {
+        let ack_count = self.acklist.len();
+        self.ikcp_flush_segments();
+        let result = self.ikcp_output();
+
+        // output 一直写不出去时队列不能无限增长，最多保留一个发送窗口的数据包和本次的 ack，
+        // 丢掉最旧的报文并计入 stats。数据包由重传补回，ack 丢失也只会导致对端重传。
+        // sans-IO 模式下报文由调用者通过 poll_transmit 取走，不受这个限制
+        if result.is_err() {
+            let limit = (self.snd_wnd as usize + ack_count).max(1);
+            while self.output_queue.len() > limit {
+                self.output_queue.pop_front();
+                self.stats.output_dropped += 1;
+            }
+        }
+        result
+    }
+
+    // 把排队的报文写入 output，写入失败的报文留在队列中，下次 flush 时重试
+    fn ikcp_output(&mut self) -> Result<(), KcpError> {
+        while let Some(pkt) = self.output_queue.front() {
+            self.output.write_all(pkt)?;
+            self.stats.out_pkts += 1;
+            self.stats.out_bytes += pkt.len() as u64;
+            self.output_queue.pop_front();
+        }
+        Ok(())
+    }
+}
+
+// read conv from a raw datagram, None if it is shorter than a segment header
+pub fn ikcp_getconv(buf: &[u8]) -> Option<u32> {
+    if buf.len() < IKCP_OVERHEAD as usize {
+        return None;
     }
+    Some(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
 }
 
 #[inline]
 fn ibound(lower: u32, middle: u32, upper: u32) -> u32 {
-    return min(max(lower, middle), upper);
+    min(max(lower, middle), upper)
 }
 
+// 序号和时间戳都按 RFC 1982 的方式比较，回绕之后依然有效
 #[inline]
-fn diff(later: u32, earlier: u32) -> i64 {
-    later as i64 - earlier as i64
+pub(crate) fn diff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_before_update_returns_current() {
+        let kcp = Kcp::ickp_create((), 1);
+        assert_eq!(kcp.ikcp_check(1000), 1000);
+    }
+
+    #[test]
+    fn check_idle_session_sleeps() {
+        let mut kcp = Kcp::ickp_create(Vec::new(), 1);
+        kcp.ikcp_update(1000).unwrap();
+        assert_eq!(kcp.ikcp_check(1000), 1000 + IKCP_CHECK_IDLE);
+    }
+
+    #[test]
+    fn check_pending_send_waits_for_flush() {
+        let mut kcp = Kcp::ickp_create(Vec::new(), 1);
+        kcp.ikcp_update(1000).unwrap();
+        kcp.ikcp_send(b"hello").unwrap();
+        assert_eq!(kcp.ikcp_check(1010), 1000 + IKCP_INTERVAL);
+    }
+
+    #[test]
+    fn check_waits_for_resend() {
+        let mut kcp = Kcp::ickp_create(Vec::new(), 1);
+        kcp.ikcp_update(1000).unwrap();
+        kcp.ikcp_send(b"hello").unwrap();
+        kcp.ikcp_update(1100).unwrap();
+        assert_eq!(kcp.snd_buf.len(), 1);
+
+        let resendts = kcp.snd_buf[0].resendts;
+        assert_eq!(kcp.ikcp_check(1100), resendts);
+        kcp.ikcp_update(resendts).unwrap();
+        assert_eq!(kcp.snd_buf[0].xmit, 2);
+    }
+
+    #[test]
+    fn errors_are_typed() {
+        let mut kcp = Kcp::ickp_create((), 1);
+        assert!(matches!(kcp.ikcp_recv(&mut [0; 8]), Err(KcpError::Empty)));
+        assert!(matches!(kcp.ikcp_send(b""), Err(KcpError::EmptyPayload)));
+        assert!(matches!(kcp.ikcp_input(&[0; 8]), Err(KcpError::Truncated)));
+        assert!(matches!(kcp.ikcp_setmtu(10), Err(KcpError::InvalidMtu(10))));
+
+        let mut buf = BytesMut::new();
+        let seg = Segment {
+            conv: 2,
+            cmd: IKCP_CMD_WINS,
+            ..Default::default()
+        };
+        seg.encode(&mut buf);
+        assert!(matches!(
+            kcp.ikcp_input(&buf),
+            Err(KcpError::ConvMismatch {
+                expected: 1,
+                got: 2
+            })
+        ));
+
+        buf.clear();
+        let seg = Segment {
+            conv: 1,
+            cmd: 99,
+            ..Default::default()
+        };
+        seg.encode(&mut buf);
+        assert!(matches!(
+            kcp.ikcp_input(&buf),
+            Err(KcpError::UnknownCommand(99))
+        ));
+    }
+
+    struct FlakyOutput {
+        fail: usize,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl Write for FlakyOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.fail > 0 {
+                self.fail -= 1;
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            self.sent.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_keeps_rejected_packets_queued() {
+        let output = FlakyOutput {
+            fail: 1,
+            sent: Vec::new(),
+        };
+        let mut kcp = Kcp::ickp_create(output, 1);
+        kcp.ikcp_nodelay(false, 10, 0, true);
+        kcp.ikcp_send(b"hello").unwrap();
+        assert!(matches!(kcp.ikcp_update(0), Err(KcpError::Output(_))));
+        assert!(kcp.output.sent.is_empty());
+        assert_eq!(kcp.output_queue.len(), 1);
+
+        kcp.ikcp_send(b"world").unwrap();
+        kcp.ikcp_update(10).unwrap();
+        assert!(kcp.output_queue.is_empty());
+        assert_eq!(kcp.output.sent.len(), 2);
+        assert_eq!(&kcp.output.sent[0][IKCP_OVERHEAD as usize..], b"hello");
+        assert_eq!(&kcp.output.sent[1][IKCP_OVERHEAD as usize..], b"world");
+    }
+
+    #[test]
+    fn output_queue_is_capped_when_output_always_fails() {
+        let output = FlakyOutput {
+            fail: usize::MAX,
+            sent: Vec::new(),
+        };
+        let mut kcp = Kcp::ickp_create(output, 1);
+        kcp.ikcp_nodelay(true, 10, 0, true);
+        kcp.ikcp_wndsize(8, 128);
+        let mss = kcp.mss as usize;
+        for _ in 0..64 {
+            kcp.ikcp_send(&vec![0u8; mss]).unwrap();
+        }
+
+        let mut current = 0;
+        for _ in 0..100 {
+            assert!(kcp.ikcp_update(current).is_err());
+            assert!(kcp.output_queue.len() <= 8);
+            current += 10;
+        }
+        assert!(kcp.output.sent.is_empty());
+        assert!(kcp.stats().output_dropped > 0);
+
+        // output 恢复后，队列中剩下的报文能正常写出
+        kcp.output.fail = 0;
+        kcp.ikcp_flush().unwrap();
+        assert!(kcp.output_queue.is_empty());
+        assert!(!kcp.output.sent.is_empty());
+    }
+
+    #[test]
+    fn sans_io_output_is_not_capped() {
+        let mut kcp = Kcp::ickp_create((), 1);
+        kcp.ikcp_nodelay(true, 10, 0, true);
+        kcp.ikcp_wndsize(8, 128);
+        let mss = kcp.mss as usize;
+        for _ in 0..64 {
+            kcp.ikcp_send(&vec![0u8; mss]).unwrap();
+        }
+
+        // 调用者还没有取走的报文都保留在队列中
+        for i in 0..100 {
+            kcp.handle_timeout(i * 10);
+        }
+        assert!(kcp.output_queue.len() > 8);
+        assert_eq!(kcp.stats().output_dropped, 0);
+    }
+
+    #[test]
+    fn flush_packs_segments_up_to_mtu() {
+        let output = FlakyOutput {
+            fail: 0,
+            sent: Vec::new(),
+        };
+        let mut kcp = Kcp::ickp_create(output, 1);
+        kcp.ikcp_nodelay(false, 10, 0, true);
+        for _ in 0..4 {
+            kcp.ikcp_send(&[0; 500]).unwrap();
+        }
+        kcp.ikcp_update(0).unwrap();
+        // 两个 524 字节的 segment 可以放进一个 1400 的报文
+        let sizes: Vec<usize> = kcp.output.sent.iter().map(|p| p.len()).collect();
+        assert_eq!(sizes, vec![1048, 1048]);
+    }
+
+    fn session(conv: u32, start_sn: u32) -> Kcp<FlakyOutput> {
+        let output = FlakyOutput {
+            fail: 0,
+            sent: Vec::new(),
+        };
+        let mut kcp = Kcp::ickp_create(output, conv);
+        kcp.ikcp_nodelay(true, 10, 2, false);
+        kcp.ikcp_wndsize(64, 128);
+        kcp.snd_una = start_sn;
+        kcp.snd_nxt = start_sn;
+        kcp.rcv_nxt = start_sn;
+        kcp
+    }
+
+    // a 向 b 发送 total 条消息，a -> b 方向每 loss 个报文丢一个
+    fn transfer(
+        a: &mut Kcp<FlakyOutput>,
+        b: &mut Kcp<FlakyOutput>,
+        start: u32,
+        total: u32,
+        loss: u32,
+    ) {
+        let mut current = start;
+        let mut sent = 0;
+        let mut received = 0;
+        let mut packets = 0;
+        let mut buf = [0; 4];
+        for _ in 0..100000 {
+            while sent < total && a.ikcp_waitsnd() < 64 {
+                a.ikcp_send(&sent.to_le_bytes()).unwrap();
+                sent += 1;
+            }
+            a.ikcp_update(current).unwrap();
+            b.ikcp_update(current).unwrap();
+            a.check_invariants();
+            b.check_invariants();
+            for pkt in a.output.sent.drain(..) {
+                packets += 1;
+                if packets % loss != 0 {
+                    b.ikcp_input(&pkt).unwrap();
+                }
+            }
+            for pkt in b.output.sent.drain(..) {
+                a.ikcp_input(&pkt).unwrap();
+            }
+            while let Ok(n) = b.ikcp_recv(&mut buf) {
+                assert_eq!(n, 4);
+                assert_eq!(u32::from_le_bytes(buf), received);
+                received += 1;
+            }
+            if received == total {
+                return;
+            }
+            current = current.wrapping_add(10);
+        }
+        panic!("only {} of {} messages delivered", received, total);
+    }
+
+    #[test]
+    fn diff_wraps() {
+        assert_eq!(diff(0, u32::MAX), 1);
+        assert_eq!(diff(u32::MAX, 0), -1);
+        assert_eq!(diff(5, u32::MAX - 4), 10);
+        assert_eq!(diff(u32::MAX - 4, 5), -10);
+    }
 
+    #[test]
+    fn sequence_numbers_wrap() {
+        let start_sn = u32::MAX - 100;
+        let mut a = session(1, start_sn);
+        let mut b = session(1, start_sn);
+        transfer(&mut a, &mut b, 0, 2000, 5);
+        assert_eq!(a.snd_nxt, start_sn.wrapping_add(2000));
+        assert_eq!(b.rcv_nxt, start_sn.wrapping_add(2000));
+    }
 
-#[test]
-fn test(){
-    
-}
\ No newline at end of file
+    #[test]
+    fn timestamps_wrap() {
+        let mut a = session(1, 0);
+        let mut b = session(1, 0);
+        transfer(&mut a, &mut b, u32::MAX - 3000, 2000, 7);
+        assert!(a.current < u32::MAX - 3000);
+        // 时间戳回绕后 rtt 依然是一个很小的值
+        assert!(a.rx_srtt > 0 && a.rx_srtt < 100);
+        assert!(a.rx_rto < 1000);
+    }
+
+    #[test]
+    fn check_wraps() {
+        let mut kcp = session(1, 0);
+        kcp.ikcp_update(u32::MAX - 5).unwrap();
+        kcp.ikcp_send(b"hello").unwrap();
+        assert_eq!(kcp.ikcp_check(u32::MAX - 5), 4);
+        assert_eq!(kcp.ikcp_check(6), 6);
+    }
+
+    #[test]
+    fn dead_link_after_too_many_retransmits() {
+        let mut kcp = session(1, 0);
+        kcp.ikcp_deadlink(3);
+        kcp.ikcp_send(b"hello").unwrap();
+        let mut current = 0;
+        while kcp.state() == LinkState::Alive {
+            assert!(current < 60000);
+            kcp.ikcp_update(current).unwrap();
+            current += 10;
+        }
+        assert_eq!(kcp.snd_buf[0].xmit, 3);
+        assert!(matches!(kcp.ikcp_send(b"x"), Err(KcpError::DeadLink)));
+        assert!(matches!(
+            kcp.ikcp_recv(&mut [0; 8]),
+            Err(KcpError::DeadLink)
+        ));
+    }
+
+    #[test]
+    fn stream_mode_coalesces_sends() {
+        let mut kcp = Kcp::ickp_create_stream((), 1);
+        assert_eq!(kcp.ikcp_send(b"hello ").unwrap(), 6);
+        assert_eq!(kcp.ikcp_send(b"world").unwrap(), 5);
+        assert_eq!(kcp.snd_queue.len(), 1);
+        assert_eq!(kcp.snd_queue[0].len, 11);
+        assert_eq!(&kcp.snd_queue[0].data[..], b"hello world");
+
+        // 流模式不受 255 个分片的限制
+        let big = vec![0; kcp.mss as usize * 300];
+        assert_eq!(kcp.ikcp_send(&big).unwrap(), big.len());
+        assert!(kcp.snd_queue.iter().all(|seg| seg.frg == 0));
+    }
+
+    #[test]
+    fn create_with_config() {
+        let config = KcpConfig::fastest().mtu(1200).window(64, 256).stream(true);
+        let kcp = Kcp::with_config((), 1, &config).unwrap();
+        assert_eq!(kcp.mtu, 1200);
+        assert_eq!(kcp.mss, 1200 - IKCP_OVERHEAD);
+        assert_eq!((kcp.snd_wnd, kcp.rcv_wnd), (64, 256));
+        assert!(kcp.nodelay && kcp.nocwnd && kcp.stream);
+        assert_eq!((kcp.interval, kcp.fastresend), (10, 2));
+        assert_eq!(kcp.rx_minrto, IKCP_RTO_NDL);
+
+        let config = KcpConfig::default().interval(1);
+        assert!(matches!(
+            Kcp::with_config((), 1, &config),
+            Err(KcpError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn snapshot_reflects_state() {
+        let mut a = session(1, 0);
+        let mut b = session(1, 0);
+        let state = a.snapshot();
+        assert_eq!(state.rx_rto, IKCP_RTO_DEF);
+        assert_eq!((state.snd_wnd, state.rcv_wnd), (64, 128));
+        assert_eq!(state.link_state, LinkState::Alive);
+
+        transfer(&mut a, &mut b, 0, 100, 3);
+        let state = a.snapshot();
+        assert_eq!(state.snd_nxt, 100);
+        assert!(state.rx_srtt > 0);
+        assert!(state.xmit > 0);
+        assert_eq!(state.snd_queue, 0);
+        assert_eq!(b.snapshot().rcv_nxt, 100);
+    }
+
+    #[test]
+    fn stats_count_traffic_and_retransmits() {
+        let mut a = session(1, 0);
+        let mut b = session(1, 0);
+        transfer(&mut a, &mut b, 0, 200, 4);
+
+        let sa = *a.stats();
+        let sb = *b.stats();
+        assert!(sa.lost_segs + sa.fast_retrans_segs > 0);
+        assert_eq!(sa.out_data_segs, 200 + sa.lost_segs + sa.fast_retrans_segs);
+        assert!(sa.out_pkts > 0 && sa.out_bytes > sa.out_data_bytes);
+        // a -> b 方向有丢包
+        assert!(sb.in_data_segs >= 200 && sb.in_data_segs < sa.out_data_segs);
+        assert!(sa.acks_received > 0 && sb.acks_sent >= sa.acks_received);
+        assert_eq!(sa.input_errors, 0);
+
+        // 重复的报文
+        b.reset_stats();
+        let mut pkt = BytesMut::new();
+        Segment {
+            conv: 1,
+            cmd: IKCP_CMD_PUSH,
+            sn: 0,
+            len: 1,
+            data: Bytes::from_static(&[1]),
+            ..Default::default()
+        }
+        .encode(&mut pkt);
+        b.ikcp_input(&pkt).unwrap();
+        assert_eq!(b.stats().repeat_segs, 1);
+
+        // 超出接收窗口的报文
+        pkt.clear();
+        Segment {
+            conv: 1,
+            cmd: IKCP_CMD_PUSH,
+            sn: 10000,
+            ..Default::default()
+        }
+        .encode(&mut pkt);
+        b.ikcp_input(&pkt).unwrap();
+        assert_eq!(b.stats().out_of_window_segs, 1);
+
+        assert!(b.ikcp_input(&[0; 3]).is_err());
+        assert_eq!(b.stats().input_errors, 1);
+        assert_eq!(b.stats().in_pkts, 3);
+    }
+
+    #[test]
+    fn sans_io_round_trip() {
+        let mut a = Kcp::ickp_create((), 1);
+        let mut b = Kcp::ickp_create((), 1);
+        a.ikcp_nodelay(true, 10, 2, true);
+        b.ikcp_nodelay(true, 10, 2, true);
+        assert_eq!(a.poll_timeout(), 0);
+
+        let msg = vec![7; 3000];
+        a.ikcp_send(&msg).unwrap();
+        let mut now = 0;
+        let mut buf = [0; 4096];
+        loop {
+            assert!(now < 1000);
+            a.handle_timeout(now);
+            b.handle_timeout(now);
+            while let Some(pkt) = a.poll_transmit() {
+                assert!(pkt.len() <= a.mtu as usize);
+                b.ikcp_input(&pkt).unwrap();
+            }
+            while let Some(pkt) = b.poll_transmit() {
+                a.ikcp_input(&pkt).unwrap();
+            }
+            if let Ok(n) = b.ikcp_recv(&mut buf) {
+                assert_eq!(&buf[..n], &msg[..]);
+                break;
+            }
+            now = a.poll_timeout();
+        }
+        assert_eq!(a.stats().out_pkts, 3);
+    }
+
+    // 固定窗口，记录收到的确认
+    struct FixedWindow {
+        wnd: u32,
+        acked: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    }
+
+    impl CongestionController for FixedWindow {
+        fn on_ack(&mut self, ack: &AckEvent) {
+            self.acked
+                .fetch_add(ack.acked_segs as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+        fn on_fast_retransmit(&mut self, _: u32, _: u32, _: u32) {}
+        fn on_timeout_loss(&mut self, _: u32, _: u32) {}
+        fn window(&self) -> u32 {
+            self.wnd
+        }
+    }
+
+    #[test]
+    fn custom_congestion_controller() {
+        let acked = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let mut a = session(1, 0);
+        let mut b = session(1, 0);
+        a.set_congestion_controller(FixedWindow {
+            wnd: 3,
+            acked: acked.clone(),
+        });
+        assert_eq!(a.snapshot().cwnd, 3);
+        transfer(&mut a, &mut b, 0, 100, 5);
+        // 最后的 ack 可能还没有回到 a
+        let n = acked.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(n as usize + a.ikcp_waitsnd(), 100);
+
+        // snd_buf 中的 segment 数不超过拥塞窗口
+        let mut c = session(2, 0);
+        c.set_congestion_controller(FixedWindow { wnd: 3, acked });
+        for i in 0..10u32 {
+            c.ikcp_send(&i.to_le_bytes()).unwrap();
+        }
+        c.ikcp_update(0).unwrap();
+        assert_eq!(c.snapshot().snd_buf, 3);
+    }
+
+    // 把 segment 编码成一个报文
+    fn packet(segs: &[Segment]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for seg in segs {
+            seg.encode(&mut buf);
+        }
+        buf
+    }
+
+    fn push(sn: u32, frg: u8, data: &[u8]) -> Segment {
+        Segment {
+            conv: 1,
+            cmd: IKCP_CMD_PUSH,
+            frg,
+            wnd: 128,
+            sn,
+            len: data.len() as u32,
+            data: Bytes::copy_from_slice(data),
+            ..Default::default()
+        }
+    }
+
+    fn ack(sn: u32, una: u32, ts: u32) -> Segment {
+        Segment {
+            conv: 1,
+            cmd: IKCP_CMD_ACK,
+            wnd: 128,
+            ts,
+            sn,
+            una,
+            ..Default::default()
+        }
+    }
+
+    // 不受拥塞窗口限制的会话，第一次 flush 就会把数据全部发出
+    fn nocwnd_session(nodelay: bool) -> Kcp<()> {
+        let mut kcp = Kcp::ickp_create((), 1);
+        kcp.ikcp_nodelay(nodelay, 10, 0, true);
+        kcp
+    }
+
+    fn sns(queue: &VecDeque<Segment>) -> Vec<u32> {
+        queue.iter().map(|seg| seg.sn).collect()
+    }
+
+    #[test]
+    fn fragmentation_and_reassembly() {
+        let mut a = nocwnd_session(false);
+        let mut b = nocwnd_session(false);
+        let msg: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        assert_eq!(a.ikcp_send(&msg).unwrap(), 3000);
+
+        // 分片编号由大到小，0 表示最后一个分片
+        let frgs: Vec<u8> = a.snd_queue.iter().map(|seg| seg.frg).collect();
+        let lens: Vec<u32> = a.snd_queue.iter().map(|seg| seg.len).collect();
+        assert_eq!(frgs, vec![2, 1, 0]);
+        assert_eq!(lens, vec![1376, 1376, 248]);
+
+        a.handle_timeout(0);
+        let pkts: Vec<Bytes> = std::iter::from_fn(|| a.poll_transmit()).collect();
+        assert_eq!(pkts.len(), 3);
+
+        // 分片没有到齐之前消息不可读
+        let mut buf = [0; 4096];
+        b.ikcp_input(&pkts[0]).unwrap();
+        b.ikcp_input(&pkts[1]).unwrap();
+        assert_eq!(b.rcv_queue.len(), 2);
+        assert!(matches!(b.ikcp_peeksize(), Err(KcpError::Empty)));
+        assert!(matches!(b.ikcp_recv(&mut buf), Err(KcpError::Empty)));
+
+        b.ikcp_input(&pkts[2]).unwrap();
+        assert_eq!(b.ikcp_peeksize().unwrap(), 3000);
+        assert!(matches!(
+            b.ikcp_recv(&mut buf[..2999]),
+            Err(KcpError::BufferTooSmall { needed: 3000 })
+        ));
+        assert_eq!(b.rcv_queue.len(), 3);
+        assert_eq!(b.ikcp_recv(&mut buf).unwrap(), 3000);
+        assert_eq!(&buf[..3000], &msg[..]);
+        assert!(b.rcv_queue.is_empty());
+
+        // 对端发来的 frg 为 255 时不会溢出
+        b.ikcp_input(&packet(&[push(3, 255, &[0])])).unwrap();
+        assert!(matches!(b.ikcp_peeksize(), Err(KcpError::Empty)));
+
+        // 正好一个 mss 的消息不分片
+        let mut c = nocwnd_session(false);
+        c.ikcp_send(&[0; 1376]).unwrap();
+        assert_eq!(c.snd_queue.len(), 1);
+        assert_eq!(c.snd_queue[0].frg, 0);
+
+        // 与 C 版一样分片数必须小于 IKCP_WND_RCV
+        assert_eq!(c.max_message_size(), 1376 * 127);
+        assert!(c.ikcp_send(&vec![0; 1376 * 127]).is_ok());
+        assert!(matches!(
+            c.ikcp_send(&vec![0; 1376 * 127 + 1]),
+            Err(KcpError::TooManyFragments)
+        ));
+    }
+
+    #[test]
+    fn stream_mode_fills_last_segment() {
+        let mut kcp = Kcp::ickp_create_stream((), 1);
+        kcp.ikcp_nodelay(false, 10, 0, true);
+        let mss = kcp.mss as usize;
+
+        // 先把 snd_queue 最后一个 segment 填满 mss，剩下的数据再新建 segment
+        kcp.ikcp_send(&vec![1; mss - 10]).unwrap();
+        kcp.ikcp_send(&[2; 30]).unwrap();
+        let lens: Vec<u32> = kcp.snd_queue.iter().map(|seg| seg.len).collect();
+        assert_eq!(lens, vec![mss as u32, 20]);
+        assert_eq!(&kcp.snd_queue[0].data[mss - 10..], &[2; 10]);
+        assert!(kcp.snd_queue.iter().all(|seg| seg.frg == 0));
+
+        // 已经进入 snd_buf 的 segment 不再合并
+        kcp.handle_timeout(0);
+        assert!(kcp.snd_queue.is_empty());
+        kcp.ikcp_send(b"x").unwrap();
+        assert_eq!(kcp.snd_queue.len(), 1);
+        assert_eq!(kcp.snd_buf.len(), 2);
+
+        // 接收方每次读出一个 segment
+        let mut b = Kcp::ickp_create_stream((), 1);
+        while let Some(pkt) = kcp.poll_transmit() {
+            b.ikcp_input(&pkt).unwrap();
+        }
+        let mut buf = [0; 2048];
+        assert_eq!(b.ikcp_recv(&mut buf).unwrap(), mss);
+        assert_eq!(b.ikcp_recv(&mut buf).unwrap(), 20);
+        assert!(matches!(b.ikcp_recv(&mut buf), Err(KcpError::Empty)));
+    }
+
+    // data 的内存是否位于 buf 之内
+    fn shares(buf: &[u8], data: &[u8]) -> bool {
+        buf.as_ptr_range().contains(&data.as_ptr())
+    }
+
+    #[test]
+    fn bytes_api_does_not_copy() {
+        let mut a = nocwnd_session(false);
+        let mss = a.mss as usize;
+        let message = Bytes::from((0..mss * 3).map(|i| i as u8).collect::<Vec<u8>>());
+        assert_eq!(a.send_bytes(message.clone()).unwrap(), mss * 3);
+        assert!(a.snd_queue.iter().all(|seg| shares(&message, &seg.data)));
+        a.send_bytes(Bytes::from_static(b"small")).unwrap();
+        assert!(matches!(
+            a.send_bytes(Bytes::new()),
+            Err(KcpError::EmptyPayload)
+        ));
+
+        // 收到的 segment 直接引用报文
+        let mut b = nocwnd_session(false);
+        a.handle_timeout(0);
+        while let Some(pkt) = a.poll_transmit() {
+            assert_eq!(b.input_bytes(pkt.clone()).unwrap(), pkt.len());
+            assert!(b.rcv_queue.iter().any(|seg| shares(&pkt, &seg.data)));
+        }
+
+        // 多个分片合并成一条消息，单个分片直接返回
+        assert_eq!(b.recv_bytes().unwrap(), message);
+        let small = b.recv_bytes().unwrap();
+        assert_eq!(&small[..], b"small");
+        assert_eq!(b.stats().in_pkts, 4);
+        assert!(b.recv_bytes().is_none());
+
+        // 流模式合并到最后一个 segment 时需要复制
+        let mut s = Kcp::ickp_create_stream((), 1);
+        let first = Bytes::from_static(b"hello ");
+        s.send_bytes(first.clone()).unwrap();
+        s.send_bytes(Bytes::from_static(b"world")).unwrap();
+        assert_eq!(&s.snd_queue[0].data[..], b"hello world");
+        assert!(!shares(&first, &s.snd_queue[0].data));
+    }
+
+    #[test]
+    fn recv_message_sizes_output() {
+        let mut a = nocwnd_session(false);
+        let mut b = nocwnd_session(false);
+        let mss = a.mss as usize;
+        let large: Vec<u8> = (0..mss * 5 / 2).map(|i| i as u8).collect();
+        a.ikcp_send(&large).unwrap();
+        a.ikcp_send(b"one").unwrap();
+        a.ikcp_send(b"two").unwrap();
+        a.ikcp_send(b"three").unwrap();
+        a.handle_timeout(0);
+        while let Some(pkt) = a.poll_transmit() {
+            b.input_bytes(pkt).unwrap();
+        }
+
+        // 缓冲区太小时 ikcp_recv 返回需要的大小，消息仍然留在队列中
+        let mut small = [0; 100];
+        assert!(matches!(
+            b.ikcp_recv(&mut small),
+            Err(KcpError::BufferTooSmall { needed }) if needed == large.len()
+        ));
+        assert_eq!(b.recv_message().unwrap(), large);
+
+        // recv_into 追加到 buf 的末尾
+        let mut buf = b"x".to_vec();
+        assert_eq!(b.recv_into(&mut buf).unwrap(), 3);
+        assert_eq!(buf, b"xone");
+
+        let rest: Vec<Vec<u8>> = b.messages().collect();
+        assert_eq!(rest, vec![b"two".to_vec(), b"three".to_vec()]);
+        assert!(matches!(b.recv_message(), Err(KcpError::Empty)));
+        assert!(matches!(b.recv_into(&mut buf), Err(KcpError::Empty)));
+        assert_eq!(buf, b"xone");
+    }
+
+    #[test]
+    fn out_of_order_insertion() {
+        let mut kcp = Kcp::ickp_create((), 1);
+
+        // 乱序到达的 segment 按 sn 插入 rcv_buf
+        for sn in [3, 1, 2] {
+            kcp.ikcp_input(&packet(&[push(sn, 0, &[sn as u8])]))
+                .unwrap();
+        }
+        assert_eq!(sns(&kcp.rcv_buf), vec![1, 2, 3]);
+        assert_eq!(kcp.rcv_nxt, 0);
+        assert!(kcp.rcv_queue.is_empty());
+        assert_eq!(kcp.acklist.len(), 3);
+
+        // 重复的 segment 依然回复 ack，但是不会再次插入
+        kcp.ikcp_input(&packet(&[push(2, 0, &[2])])).unwrap();
+        assert_eq!(sns(&kcp.rcv_buf), vec![1, 2, 3]);
+        assert_eq!(kcp.stats().repeat_segs, 1);
+        assert_eq!(kcp.acklist.len(), 4);
+
+        // 缺失的 sn 到达后连续的数据全部移到 rcv_queue
+        kcp.ikcp_input(&packet(&[push(0, 0, &[0])])).unwrap();
+        assert_eq!(kcp.rcv_nxt, 4);
+        assert!(kcp.rcv_buf.is_empty());
+        assert_eq!(sns(&kcp.rcv_queue), vec![0, 1, 2, 3]);
+        let mut buf = [0; 8];
+        for i in 0..4 {
+            assert_eq!(kcp.ikcp_recv(&mut buf).unwrap(), 1);
+            assert_eq!(buf[0], i);
+        }
+
+        // 已经接收过的 sn 需要再次确认，以防之前的 ack 丢失
+        kcp.ikcp_input(&packet(&[push(1, 0, &[1])])).unwrap();
+        assert_eq!(kcp.stats().repeat_segs, 2);
+        assert_eq!(kcp.acklist.len(), 6);
+        assert_eq!(kcp.acklist[5].0, 1);
+
+        // 超出接收窗口的 segment 既不接收也不确认
+        let sn = kcp.rcv_nxt + kcp.rcv_wnd;
+        kcp.ikcp_input(&packet(&[push(sn, 0, &[0])])).unwrap();
+        assert_eq!(kcp.stats().out_of_window_segs, 1);
+        assert_eq!(kcp.acklist.len(), 6);
+        kcp.ikcp_input(&packet(&[push(sn - 1, 0, &[0])])).unwrap();
+        assert_eq!(sns(&kcp.rcv_buf), vec![sn - 1]);
+        assert_eq!(kcp.acklist.len(), 7);
+    }
+
+    #[test]
+    fn una_and_ack_processing() {
+        let mut kcp = nocwnd_session(false);
+        for i in 0..5u8 {
+            kcp.ikcp_send(&[i]).unwrap();
+        }
+        kcp.handle_timeout(1000);
+        assert_eq!(sns(&kcp.snd_buf), vec![0, 1, 2, 3, 4]);
+
+        // ack 只确认指定的 sn，snd_una 停在第一个未确认的 segment
+        kcp.ikcp_input(&packet(&[ack(2, 0, 1000)])).unwrap();
+        assert_eq!(sns(&kcp.snd_buf), vec![0, 1, 3, 4]);
+        assert_eq!(kcp.snd_una, 0);
+
+        kcp.ikcp_input(&packet(&[ack(0, 0, 1000)])).unwrap();
+        assert_eq!(sns(&kcp.snd_buf), vec![1, 3, 4]);
+        assert_eq!(kcp.snd_una, 1);
+
+        // 范围之外的 ack 被忽略
+        kcp.ikcp_input(&packet(&[ack(7, 1, 1000)])).unwrap();
+        assert_eq!(sns(&kcp.snd_buf), vec![1, 3, 4]);
+
+        // una 确认之前的全部 segment，任何命令都会携带 una 和 wnd
+        let mut wins = ack(0, 4, 0);
+        wins.cmd = IKCP_CMD_WINS;
+        wins.wnd = 77;
+        kcp.ikcp_input(&packet(&[wins])).unwrap();
+        assert_eq!(sns(&kcp.snd_buf), vec![4]);
+        assert_eq!(kcp.snd_una, 4);
+        assert_eq!(kcp.rmt_wnd, 77);
+
+        kcp.ikcp_input(&packet(&[ack(4, 5, 1000)])).unwrap();
+        assert!(kcp.snd_buf.is_empty());
+        assert_eq!(kcp.snd_una, kcp.snd_nxt);
+    }
+
+    #[test]
+    fn ack_updates_rtt() {
+        let mut kcp = nocwnd_session(false);
+        kcp.ikcp_send(b"hello").unwrap();
+        kcp.handle_timeout(1000);
+
+        // 时间戳在未来的 ack 不参与 rtt 计算
+        kcp.ikcp_input(&packet(&[ack(0, 0, 1010)])).unwrap();
+        assert_eq!((kcp.rx_srtt, kcp.rx_rto), (0, IKCP_RTO_DEF));
+
+        // 第一个样本：srtt = rtt，rttval = rtt / 2
+        kcp.current = 1300;
+        kcp.ikcp_input(&packet(&[ack(9, 0, 1000)])).unwrap();
+        assert_eq!((kcp.rx_srtt, kcp.rx_rttval), (300, 150));
+        assert_eq!(kcp.rx_rto, 300 + 4 * 150);
+
+        // 之后按 1/8 和 1/4 平滑
+        kcp.current = 1400;
+        kcp.ikcp_input(&packet(&[ack(9, 0, 1300)])).unwrap();
+        assert_eq!(kcp.rx_srtt, (7 * 300 + 100) / 8);
+        assert_eq!(kcp.rx_rttval, (3 * 150 + 200) / 4);
+        assert_eq!(kcp.rx_rto, 275 + 4 * 162);
+
+        // rto 不小于 rx_minrto
+        for _ in 0..50 {
+            kcp.ikcp_input(&packet(&[ack(9, 0, 1400)])).unwrap();
+        }
+        assert_eq!(kcp.rx_rto, IKCP_RTO_MIN);
+    }
+
+    #[test]
+    fn fast_retransmit_via_fastack() {
+        let mut kcp = Kcp::ickp_create((), 1);
+        kcp.ikcp_nodelay(true, 10, 2, true);
+        for i in 0..5u8 {
+            kcp.ikcp_send(&[i]).unwrap();
+        }
+        kcp.handle_timeout(0);
+        while kcp.poll_transmit().is_some() {}
+
+        // 只有 sn 小于最大 ack 的 segment 被跳过
+        kcp.ikcp_input(&packet(&[ack(1, 0, 0)])).unwrap();
+        let fastack: Vec<u32> = kcp.snd_buf.iter().map(|seg| seg.fastack).collect();
+        assert_eq!(fastack, vec![1, 0, 0, 0]);
+
+        // 一个报文中的多个 ack 只按其中最大的 sn 计算一次
+        kcp.ikcp_input(&packet(&[ack(3, 0, 0), ack(2, 0, 0)]))
+            .unwrap();
+        assert_eq!(sns(&kcp.snd_buf), vec![0, 4]);
+        let fastack: Vec<u32> = kcp.snd_buf.iter().map(|seg| seg.fastack).collect();
+        assert_eq!(fastack, vec![2, 0]);
+
+        // 跳过次数达到 fastresend 后，下一次 flush 立即重传，不等待超时
+        kcp.handle_timeout(10);
+        assert_eq!(kcp.stats().fast_retrans_segs, 1);
+        assert_eq!(kcp.stats().lost_segs, 0);
+        assert_eq!((kcp.snd_buf[0].xmit, kcp.snd_buf[0].fastack), (2, 0));
+        assert_eq!(kcp.snd_buf[0].resendts, 10 + kcp.snd_buf[0].rto);
+        assert_eq!(kcp.snd_buf[1].xmit, 1);
+        let pkt = kcp.poll_transmit().unwrap();
+        assert_eq!(pkt.len(), IKCP_OVERHEAD as usize + 1);
+        assert_eq!(pkt[IKCP_OVERHEAD as usize], 0);
+
+        // fastresend 为 0 时不进行快速重传
+        let mut kcp = nocwnd_session(true);
+        for i in 0..3u8 {
+            kcp.ikcp_send(&[i]).unwrap();
+        }
+        kcp.handle_timeout(0);
+        kcp.ikcp_input(&packet(&[ack(2, 0, 0)])).unwrap();
+        for _ in 0..9 {
+            kcp.ikcp_input(&packet(&[ack(1, 0, 0)])).unwrap();
+        }
+        assert_eq!(kcp.snd_buf[0].fastack, 10);
+        kcp.handle_timeout(10);
+        assert_eq!(kcp.snd_buf[0].xmit, 1);
+        assert_eq!(kcp.stats().fast_retrans_segs, 0);
+
+        // fastack_conserve 时只采用发送时间更晚的 ack，并且只计算在它之前发送的 segment
+        for (conserve, expected) in [(false, vec![1, 1, 1]), (true, vec![0, 1, 0])] {
+            let mut kcp = nocwnd_session(true);
+            kcp.set_fastack_conserve(conserve);
+            for i in 0..5u8 {
+                kcp.ikcp_send(&[i]).unwrap();
+            }
+            kcp.handle_timeout(0);
+            // sn 0 在 ack 回显的时间之后重传过
+            kcp.snd_buf[0].ts = 100;
+            kcp.ikcp_input(&packet(&[ack(2, 0, 5), ack(4, 0, 0)]))
+                .unwrap();
+            assert_eq!(sns(&kcp.snd_buf), vec![0, 1, 3]);
+            let fastack: Vec<u32> = kcp.snd_buf.iter().map(|seg| seg.fastack).collect();
+            assert_eq!(fastack, expected);
+        }
+    }
+
+    #[test]
+    fn zero_window_probe_cycle() {
+        let mut a = nocwnd_session(true);
+        let mut b = nocwnd_session(true);
+        a.ikcp_wndsize(256, 128);
+        for i in 0..128u32 {
+            a.ikcp_send(&i.to_le_bytes()).unwrap();
+        }
+
+        // b 不读取数据，接收队列满了之后通告的窗口为 0
+        let mut now = 0;
+        let exchange = |a: &mut Kcp<()>, b: &mut Kcp<()>, now: u32| {
+            a.handle_timeout(now);
+            b.handle_timeout(now);
+            while let Some(pkt) = a.poll_transmit() {
+                b.ikcp_input(&pkt).unwrap();
+            }
+            while let Some(pkt) = b.poll_transmit() {
+                a.ikcp_input(&pkt).unwrap();
+            }
+        };
+        while a.rmt_wnd != 0 || !a.snd_buf.is_empty() {
+            assert!(now < 1000);
+            exchange(&mut a, &mut b, now);
+            now += 10;
+        }
+        assert_eq!(b.rcv_queue.len(), 128);
+        assert_eq!(b.ikcp_wnd_unused(), 0);
+        a.ikcp_send(b"more").unwrap();
+
+        // 远端窗口为 0 后，等待 7 秒发送第一个 IKCP_CMD_WASK
+        exchange(&mut a, &mut b, now);
+        let start = now;
+        assert_eq!(a.probe_wait, IKCP_PROBE_INIT);
+        assert_eq!(a.ts_probe, start + IKCP_PROBE_INIT);
+        assert_eq!(a.snd_queue.len(), 1);
+        while a.stats().wask_sent == 0 {
+            now += 10;
+            exchange(&mut a, &mut b, now);
+        }
+        assert_eq!(now, start + IKCP_PROBE_INIT);
+        let probed = now;
+
+        // 对端收到 WASK 后在下一次 flush 回复 IKCP_CMD_WINS，窗口依然为 0 时探测间隔增加到 1.5 倍
+        now += 10;
+        exchange(&mut a, &mut b, now);
+        assert_eq!(b.stats().wask_received, 1);
+        assert_eq!(b.stats().wins_sent, 1);
+        assert_eq!(a.stats().wins_received, 1);
+        assert_eq!(a.rmt_wnd, 0);
+        assert_eq!(a.probe_wait, IKCP_PROBE_INIT * 3 / 2);
+        assert_eq!(a.ts_probe, probed + IKCP_PROBE_INIT * 3 / 2);
+
+        // 对端读取数据后主动通告窗口，探测状态复位，数据继续发送
+        let mut buf = [0; 8];
+        let mut received = 0;
+        while b.ikcp_recv(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 128);
+        now += 10;
+        exchange(&mut a, &mut b, now);
+        assert_eq!(b.stats().wins_sent, 2);
+        assert_eq!(a.rmt_wnd, 128);
+        now += 10;
+        exchange(&mut a, &mut b, now);
+        assert_eq!((a.probe_wait, a.ts_probe), (0, 0));
+        assert_eq!(b.ikcp_recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"more");
+    }
+
+    #[test]
+    fn probe_interval_is_capped() {
+        let mut kcp = nocwnd_session(true);
+        let mut wins = ack(0, 0, 0);
+        wins.cmd = IKCP_CMD_WINS;
+        wins.wnd = 0;
+        kcp.ikcp_input(&packet(&[wins])).unwrap();
+
+        let mut waits = Vec::new();
+        kcp.handle_timeout(0);
+        while waits.len() < 10 {
+            let now = kcp.poll_timeout();
+            kcp.handle_timeout(now);
+            if kcp.poll_transmit().is_some() {
+                waits.push(kcp.probe_wait);
+            }
+        }
+        assert_eq!(
+            waits,
+            vec![10500, 15750, 23625, 35437, 53155, 79732, 119598, 120000, 120000, 120000]
+        );
+    }
+
+    // 单个 segment 每次超时重传时的 (时间, rto)
+    fn retransmits(nodelay: bool, count: usize) -> Vec<(u32, u32)> {
+        let mut kcp = nocwnd_session(nodelay);
+        kcp.ikcp_send(b"hello").unwrap();
+        kcp.handle_timeout(0);
+        let mut result = Vec::new();
+        while result.len() < count {
+            let now = kcp.poll_timeout();
+            kcp.handle_timeout(now);
+            let seg = &kcp.snd_buf[0];
+            if seg.xmit as usize == result.len() + 2 {
+                result.push((now, seg.rto));
+            }
+        }
+        assert_eq!(kcp.stats().lost_segs, count as u64);
+        result
+    }
+
+    #[test]
+    fn rto_backoff() {
+        // 普通模式：第一次超时额外等待 rto / 8，之后每次 rto 翻倍
+        assert_eq!(
+            retransmits(false, 4),
+            vec![(225, 400), (625, 800), (1425, 1600), (3025, 3200)]
+        );
+        // nodelay 模式：每次增加一半
+        assert_eq!(
+            retransmits(true, 4),
+            vec![(200, 300), (500, 450), (950, 675), (1625, 1012)]
+        );
+
+        // 重传使用 segment 自己的 rto，rx_rto 较大时普通模式至少增加 rx_rto
+        let mut kcp = nocwnd_session(false);
+        kcp.ikcp_send(b"hello").unwrap();
+        kcp.handle_timeout(0);
+        kcp.rx_rto = 1000;
+        kcp.handle_timeout(225);
+        assert_eq!(kcp.snd_buf[0].rto, 1200);
+        assert_eq!(kcp.snd_buf[0].resendts, 1425);
+    }
+
+    #[test]
+    fn mtu_changes() {
+        let mut kcp = nocwnd_session(false);
+        assert!(matches!(kcp.ikcp_setmtu(49), Err(KcpError::InvalidMtu(49))));
+        assert_eq!(
+            (kcp.mtu, kcp.mss),
+            (IKCP_MTU_DEF, IKCP_MTU_DEF - IKCP_OVERHEAD)
+        );
+
+        // 修改之前已经分好的 segment 不会重新分片，单独作为一个报文发送
+        kcp.ikcp_send(&[0; 1376]).unwrap();
+        kcp.ikcp_setmtu(500).unwrap();
+        assert_eq!((kcp.mtu, kcp.mss), (500, 476));
+        kcp.ikcp_send(&[0; 1000]).unwrap();
+        let lens: Vec<u32> = kcp.snd_queue.iter().map(|seg| seg.len).collect();
+        assert_eq!(lens, vec![1376, 476, 476, 48]);
+
+        kcp.handle_timeout(0);
+        let sizes: Vec<usize> = std::iter::from_fn(|| kcp.poll_transmit())
+            .map(|pkt| pkt.len())
+            .collect();
+        assert_eq!(sizes, vec![1400, 500, 500, 72]);
+
+        // ack 同样按照新的 mtu 打包
+        let mut b = nocwnd_session(false);
+        b.ikcp_setmtu(50).unwrap();
+        for sn in 0..5 {
+            b.ikcp_input(&packet(&[push(sn, 0, &[0])])).unwrap();
+        }
+        b.handle_timeout(0);
+        let sizes: Vec<usize> = std::iter::from_fn(|| b.poll_transmit())
+            .map(|pkt| pkt.len())
+            .collect();
+        assert_eq!(sizes, vec![48, 48, 24]);
+    }
+
+    // 随机生成的报文和调用序列不会 panic，也不会破坏队列的不变量，
+    // 和 fuzz/ 下的目标相同，只是使用固定的种子以便在 cargo test 中运行
+    #[test]
+    fn random_input_keeps_invariants() {
+        use crate::sim::SimRng;
+
+        let mut rng = SimRng::new(0x5eed);
+        for _ in 0..200 {
+            let mut kcp = Kcp::ickp_create((), 1);
+            kcp.ikcp_nodelay(rng.chance(0.5), 10, rng.below(3), rng.chance(0.5));
+            let mut now = rng.next_u64() as u32;
+            let mut buf = vec![0; 4096];
+            for _ in 0..200 {
+                match rng.below(6) {
+                    0 => {
+                        // 完全随机的字节
+                        let mut pkt = vec![0; rng.below(64) as usize];
+                        pkt.iter_mut().for_each(|b| *b = rng.next_u64() as u8);
+                        if pkt.len() >= 4 {
+                            pkt[..4].copy_from_slice(&1u32.to_le_bytes());
+                        }
+                        let _ = kcp.ikcp_input(&pkt);
+                    }
+                    1 | 2 => {
+                        // 头部合法、字段随机的 segment，sn 和 una 落在窗口附近
+                        let state = kcp.snapshot();
+                        let near = |base: u32, rng: &mut SimRng| {
+                            base.wrapping_add(rng.below(300)).wrapping_sub(150)
+                        };
+                        let data = vec![0; rng.below(32) as usize];
+                        let mut seg = push(near(state.rcv_nxt, &mut rng), 0, &data);
+                        seg.cmd = IKCP_CMD_PUSH + rng.below(4) as u8;
+                        seg.frg = rng.next_u64() as u8;
+                        seg.wnd = rng.next_u64() as u16;
+                        seg.ts = now.wrapping_sub(rng.next_u64() as u32 >> rng.below(31));
+                        seg.una = near(state.snd_una, &mut rng);
+                        if seg.cmd == IKCP_CMD_ACK {
+                            seg.sn = near(state.snd_una, &mut rng);
+                        }
+                        let _ = kcp.ikcp_input(&packet(&[seg]));
+                    }
+                    3 => {
+                        let n = rng.below(3000) as usize + 1;
+                        let _ = kcp.ikcp_send(&buf[..n]);
+                    }
+                    4 => {
+                        let n = rng.below(4096) as usize;
+                        let _ = kcp.ikcp_peeksize();
+                        let _ = kcp.ikcp_recv(&mut buf[..n]);
+                    }
+                    _ => {
+                        now = now.wrapping_add(rng.below(200));
+                        kcp.handle_timeout(now);
+                        kcp.check_invariants();
+                        // 一部分报文发回给自己
+                        while let Some(pkt) = kcp.poll_transmit() {
+                            if rng.chance(0.3) {
+                                let _ = kcp.ikcp_input(&pkt);
+                            }
+                        }
+                    }
+                }
+                kcp.check_invariants();
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::congestion::{AckEvent, CongestionController};
+use crate::kcp::diff;
+use std::collections::VecDeque;
+
+// 默认的目标排队时延，RFC 6817 要求不超过 100ms
+const TARGET_DEF: u32 = 100;
+
+// 每个 rtt 窗口最多增长 1 个 segment
+const GAIN: f64 = 1.0;
+
+// 基础时延取最近 10 分钟内每分钟最小值中的最小值
+const BASE_HISTORY: usize = 10;
+const BASE_INTERVAL: u32 = 60000;
+
+// 当前时延取最近 4 个样本的最小值，过滤抖动
+const CURRENT_FILTER: usize = 4;
+
+const MIN_CWND: f64 = 2.0;
+const INIT_CWND: f64 = 2.0;
+
+// 基于排队时延的低优先级拥塞控制，参考 LEDBAT（RFC 6817）
+//
+// 用 ikcp_input 中 ack 回显的 ts 计算 rtt，rtt 减去历史最小 rtt 作为排队时延。
+// 排队时延低于目标时增大窗口，超过目标时按比例减小，因此会把带宽让给
+// 同一链路上使用丢包算法的会话。
+#[derive(Debug, Clone)]
+pub struct LedbatCongestion {
+    // 目标排队时延，毫秒
+    target: u32,
+    cwnd: f64,
+
+    // (分钟, 该分钟内最小的 rtt)
+    base_delays: VecDeque<(u32, u32)>,
+    current_delays: VecDeque<u32>,
+
+    // 最近一次收到确认的时间和平滑 rtt，用来保证每个 rtt 最多减半一次
+    current: u32,
+    srtt: u32,
+    ts_reduce: Option<u32>,
+}
+
+impl Default for LedbatCongestion {
+    fn default() -> Self {
+        Self::with_target(TARGET_DEF)
+    }
+}
+
+impl LedbatCongestion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 指定目标排队时延，毫秒
+    pub fn with_target(target: u32) -> Self {
+        LedbatCongestion {
+            target: target.max(1),
+            cwnd: INIT_CWND,
+            base_delays: VecDeque::new(),
+            current_delays: VecDeque::new(),
+            current: 0,
+            srtt: 0,
+            ts_reduce: None,
+        }
+    }
+
+    pub fn target(&self) -> u32 {
+        self.target
+    }
+
+    // 当前估计的排队时延，没有样本时为 0
+    pub fn queuing_delay(&self) -> u32 {
+        let base = self.base_delays.iter().map(|&(_, d)| d).min();
+        let current = self.current_delays.iter().copied().min();
+        match (base, current) {
+            (Some(base), Some(current)) => current.saturating_sub(base),
+            _ => 0,
+        }
+    }
+
+    fn update_delays(&mut self, now: u32, rtt: u32) {
+        let minute = now / BASE_INTERVAL;
+        match self.base_delays.back_mut() {
+            Some((m, d)) if *m == minute => *d = (*d).min(rtt),
+            _ => {
+                self.base_delays.push_back((minute, rtt));
+                if self.base_delays.len() > BASE_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+
+        self.current_delays.push_back(rtt);
+        if self.current_delays.len() > CURRENT_FILTER {
+            self.current_delays.pop_front();
+        }
+    }
+
+    // 每个 rtt 最多减半一次
+    fn reduce(&mut self) {
+        if let Some(ts) = self.ts_reduce {
+            if diff(self.current, ts) < self.srtt as i32 {
+                return;
+            }
+        }
+        self.ts_reduce = Some(self.current);
+        self.cwnd = (self.cwnd / 2.0).max(MIN_CWND);
+    }
+}
+
+impl CongestionController for LedbatCongestion {
+    fn on_ack(&mut self, ack: &AckEvent) {
+        self.current = ack.current;
+        self.srtt = ack.srtt;
+        if let Some(rtt) = ack.rtt {
+            self.update_delays(ack.current, rtt);
+        }
+        if ack.acked_segs == 0 || self.current_delays.is_empty() {
+            return;
+        }
+
+        let queuing_delay = self.queuing_delay() as f64;
+        let off_target = (self.target as f64 - queuing_delay) / self.target as f64;
+        self.cwnd += GAIN * off_target * ack.acked_segs as f64 / self.cwnd;
+
+        // 不超过确认前的在途数据加 1，避免在应用没有数据时窗口无限增长
+        let flight = (ack.inflight + ack.acked_segs + 1) as f64;
+        self.cwnd = self.cwnd.min(flight).min(ack.rmt_wnd as f64).max(MIN_CWND);
+    }
+
+    fn on_fast_retransmit(&mut self, _inflight: u32, _resent: u32, _mss: u32) {
+        self.reduce();
+    }
+
+    fn on_timeout_loss(&mut self, _cwnd: u32, _mss: u32) {
+        self.ts_reduce = Some(self.current);
+        self.cwnd = 1.0;
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KcpConfig;
+    use crate::congestion::CongestionMode;
+    use crate::kcp::{ikcp_getconv, Kcp};
+    use crate::sim::{LinkConfig, SimLink};
+
+    fn ack(current: u32, rtt: u32, inflight: u32) -> AckEvent {
+        AckEvent {
+            current,
+            una_advanced: true,
+            acked_segs: 1,
+            acked_bytes: 1000,
+            rtt: Some(rtt),
+            srtt: rtt,
+            inflight,
+            mss: 1000,
+            rmt_wnd: 256,
+        }
+    }
+
+    #[test]
+    fn backs_off_when_delay_rises() {
+        let mut cc = LedbatCongestion::with_target(50);
+        for i in 0..500 {
+            cc.on_ack(&ack(i, 40, 100));
+        }
+        assert_eq!(cc.queuing_delay(), 0);
+        let grown = cc.window();
+        assert!(grown > 20);
+
+        // 排队时延超过目标后窗口减小
+        for i in 500..1000 {
+            cc.on_ack(&ack(i, 140, 100));
+        }
+        assert_eq!(cc.queuing_delay(), 100);
+        assert!(cc.window() < grown / 2);
+        assert!(cc.window() >= MIN_CWND as u32);
+
+        // 一个 rtt 内的多次快速重传只减半一次
+        let mut cc = LedbatCongestion::new();
+        for i in 0..500 {
+            cc.on_ack(&ack(i, 40, 100));
+        }
+        let wnd = cc.window();
+        cc.on_fast_retransmit(0, 2, 1000);
+        cc.on_fast_retransmit(0, 2, 1000);
+        assert_eq!(cc.window(), wnd / 2);
+    }
+
+    // 两个会话共享同一条瓶颈链路 duration 毫秒，返回各自接收到的字节数
+    fn share(modes: [CongestionMode; 2], duration: u32) -> [usize; 2] {
+        let mut senders = Vec::new();
+        let mut receivers = Vec::new();
+        for (i, mode) in modes.iter().enumerate() {
+            let config = KcpConfig::default()
+                .interval(10)
+                .fast_resend(2)
+                .window(256, 256)
+                .dead_link(1000)
+                .congestion(*mode);
+            senders.push(Kcp::with_config((), i as u32, &config).unwrap());
+            receivers.push(Kcp::with_config((), i as u32, &config).unwrap());
+        }
+        // 单向时延 30ms，瓶颈带宽 500KB/s，队列最多 300ms
+        let link = LinkConfig::default().delay(30).bandwidth(500_000);
+        let mut forward = SimLink::new(link.clone().queue_delay(300), 0x9e3779b97f4a7c15);
+        let mut backward = SimLink::new(link.queue_delay(100), 0xd1b54a32d192ed03);
+        let msg = [0u8; 1000];
+        let mut buf = [0u8; 2000];
+        let mut received = [0; 2];
+
+        for now in 0..duration {
+            for (a, b) in senders.iter_mut().zip(receivers.iter_mut()) {
+                while a.ikcp_waitsnd() < 512 {
+                    a.ikcp_send(&msg).unwrap();
+                }
+                a.handle_timeout(now);
+                b.handle_timeout(now);
+                while let Some(pkt) = a.poll_transmit() {
+                    forward.send(now, pkt);
+                }
+                while let Some(pkt) = b.poll_transmit() {
+                    backward.send(now, pkt);
+                }
+            }
+            while let Some(pkt) = forward.recv(now) {
+                let conv = ikcp_getconv(&pkt).unwrap() as usize;
+                receivers[conv].ikcp_input(&pkt).unwrap();
+            }
+            while let Some(pkt) = backward.recv(now) {
+                let conv = ikcp_getconv(&pkt).unwrap() as usize;
+                senders[conv].ikcp_input(&pkt).unwrap();
+            }
+            for (i, b) in receivers.iter_mut().enumerate() {
+                while let Ok(n) = b.ikcp_recv(&mut buf) {
+                    received[i] += n;
+                }
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn yields_to_loss_based_sessions() {
+        let [ledbat, _] = share([CongestionMode::Ledbat, CongestionMode::Ledbat], 20000);
+        let [background, interactive] =
+            share([CongestionMode::Ledbat, CongestionMode::Loss], 20000);
+        // 与同类会话公平竞争，与丢包算法的会话共享时只使用不到 40% 的带宽
+        assert!(ledbat > 500 * 20000 / 4, "ledbat pair: {} bytes", ledbat);
+        assert!(
+            background * 3 < interactive * 2,
+            "background: {} bytes, interactive: {} bytes",
+            background,
+            interactive
+        );
+    }
+}
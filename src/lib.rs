@@ -1,10 +1,41 @@
+mod bbr;
+mod chunked;
+mod config;
+mod congestion;
+#[cfg(feature = "crypto")]
+mod crypt;
+mod error;
+#[cfg(feature = "fec")]
+mod fec;
 mod kcp;
-pub use kcp::Kcp;
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        let result = 2 + 2;
-        assert_eq!(result, 4);
-    }
-}
+mod ledbat;
+#[cfg(feature = "tokio")]
+mod net;
+mod segment;
+mod server;
+mod sim;
+mod state;
+mod stats;
+mod stream;
+pub use bbr::BbrCongestion;
+pub use chunked::ChunkedKcp;
+pub use config::KcpConfig;
+pub use congestion::{AckEvent, CongestionController, CongestionMode, KcpCongestion};
+#[cfg(feature = "crypto")]
+pub use crypt::{AeadCrypt, AeadRole, BlockCrypt, CryptWriter, PacketCrypt};
+pub use error::KcpError;
+#[cfg(feature = "fec")]
+pub use fec::{FecDecoder, FecEncoder, FecWriter, FEC_OVERHEAD};
+pub use kcp::{ikcp_getconv, Kcp, LinkState, Messages};
+pub use ledbat::LedbatCongestion;
+#[cfg(feature = "tokio")]
+pub use net::{KcpListener, KcpStream};
+pub use segment::{
+    ikcp_peek_header, SegmentHeader, SegmentRef, Segments, IKCP_CMD_ACK, IKCP_CMD_PUSH,
+    IKCP_CMD_WASK, IKCP_CMD_WINS, IKCP_OVERHEAD,
+};
+pub use server::KcpServer;
+pub use sim::{LinkConfig, LinkStats, SimLink, SimRng, Simulator};
+pub use state::KcpState;
+pub use stats::KcpStats;
+pub use stream::KcpIo;
@@ -0,0 +1,507 @@
+use crate::config::KcpConfig;
+use crate::error::KcpError;
+use crate::kcp::{ikcp_getconv, Kcp, LinkState};
+use crate::segment::{SegmentRef, IKCP_CMD_PUSH, IKCP_CMD_WASK};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+// 单个 udp 报文的最大长度
+const RECV_BUF_SIZE: usize = 65536;
+
+// 等待 accept 的连接数上限，超过之后新连接的报文会被丢弃
+const ACCEPT_BACKLOG: usize = 128;
+
+// KcpListener 的收包任务和它接受的会话共享的会话表，
+// 收包任务在 KcpListener 被 drop 并且所有会话都结束之后才退出
+struct Sessions {
+    map: Mutex<HashMap<(SocketAddr, u32), Arc<Session>>>,
+
+    // 会话结束或者 KcpListener 被 drop 时唤醒收包任务，检查是否可以退出
+    changed: Notify,
+}
+
+// 一个 kcp 会话在 KcpStream、收包任务和定时任务之间共享的状态
+struct Session {
+    inner: Mutex<Inner>,
+    peer: SocketAddr,
+    conv: u32,
+    socket: Arc<UdpSocket>,
+    epoch: Instant,
+
+    // 有新的数据要发送或者 KcpStream 被关闭时唤醒定时任务
+    notify: Notify,
+}
+
+struct Inner {
+    kcp: Kcp<()>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+
+    // KcpStream 已经被 drop
+    closed: bool,
+
+    // 客户端独占的收包任务，会话结束时一起停止
+    recv_task: Option<JoinHandle<()>>,
+}
+
+impl Session {
+    fn new(kcp: Kcp<()>, conv: u32, peer: SocketAddr, socket: Arc<UdpSocket>) -> Arc<Session> {
+        Arc::new(Session {
+            inner: Mutex::new(Inner {
+                kcp,
+                read_waker: None,
+                write_waker: None,
+                closed: false,
+                recv_task: None,
+            }),
+            peer,
+            conv,
+            socket,
+            epoch: Instant::now(),
+            notify: Notify::new(),
+        })
+    }
+
+    fn now(&self) -> u32 {
+        self.epoch.elapsed().as_millis() as u32
+    }
+
+    fn input(&self, pkt: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.kcp.ikcp_input(pkt).is_ok() {
+            inner.wake();
+            // 收到的数据需要回复 ack，重新计算下一次 flush 的时间
+            self.notify.notify_one();
+        }
+    }
+}
+
+impl Inner {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // 发送队列超过两个发送窗口时让写入方等待
+    fn send_blocked(&self) -> bool {
+        self.kcp.ikcp_waitsnd() >= 2 * self.kcp.snapshot().snd_wnd as usize
+    }
+}
+
+// 按照 ikcp_check 给出的时间驱动 kcp，并把生成的报文发送出去
+async fn drive(session: Arc<Session>, sessions: Option<Arc<Sessions>>) {
+    let mut transmits = Vec::new();
+    loop {
+        let next = {
+            let mut inner = session.inner.lock().unwrap();
+            let now = session.now();
+            inner.kcp.handle_timeout(now);
+            while let Some(pkt) = inner.kcp.poll_transmit() {
+                transmits.push(pkt);
+            }
+            inner.wake();
+
+            let dead = inner.kcp.state() == LinkState::Dead;
+            if dead || (inner.closed && inner.kcp.ikcp_waitsnd() == 0) {
+                if let Some(task) = inner.recv_task.take() {
+                    task.abort();
+                }
+                break;
+            }
+            let wait = inner.kcp.poll_timeout().wrapping_sub(now) as i32;
+            Duration::from_millis(wait.max(0) as u64)
+        };
+
+        for pkt in transmits.drain(..) {
+            // udp 发送失败等同于丢包，由 kcp 负责重传
+            let _ = session.socket.send_to(&pkt, session.peer).await;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(next) => {}
+            _ = session.notify.notified() => {}
+        }
+    }
+
+    if let Some(sessions) = sessions {
+        sessions
+            .map
+            .lock()
+            .unwrap()
+            .remove(&(session.peer, session.conv));
+        sessions.changed.notify_one();
+    }
+}
+
+// 基于 tokio 的 kcp 连接，实现了 AsyncRead 和 AsyncWrite
+//
+// 消息模式下每次 poll_write 发送一条消息，读取时如果缓冲区放不下一条完整的消息，
+// 剩余的数据会留到下一次读取。
+pub struct KcpStream {
+    session: Arc<Session>,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl KcpStream {
+    fn new(session: Arc<Session>) -> KcpStream {
+        KcpStream {
+            session,
+            pending: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // 连接到 addr 上的 KcpListener，双方需要使用相同的 conv
+    pub async fn connect<A: ToSocketAddrs>(
+        addr: A,
+        conv: u32,
+        config: &KcpConfig,
+    ) -> io::Result<KcpStream> {
+        let peer = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        let local: SocketAddr = if peer.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+
+        let kcp = Kcp::with_config((), conv, config)?;
+        let session = Session::new(kcp, conv, peer, socket.clone());
+
+        let recv_session = session.clone();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![0; RECV_BUF_SIZE];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                if from == peer && ikcp_getconv(&buf[..n]) == Some(conv) {
+                    recv_session.input(&buf[..n]);
+                }
+            }
+        });
+        session.inner.lock().unwrap().recv_task = Some(recv_task);
+
+        tokio::spawn(drive(session.clone(), None));
+        Ok(KcpStream::new(session))
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.session.peer
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.session.socket.local_addr()
+    }
+
+    // 对底层 Kcp 进行只读访问，例如读取 snapshot() 和 stats()
+    pub fn with_kcp<T>(&self, f: impl FnOnce(&Kcp<()>) -> T) -> T {
+        f(&self.session.inner.lock().unwrap().kcp)
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos == this.pending.len() {
+            let mut inner = this.session.inner.lock().unwrap();
+            let size = match inner.kcp.ikcp_peeksize() {
+                Ok(size) => size as usize,
+                Err(KcpError::Empty) => {
+                    if inner.kcp.state() == LinkState::Dead {
+                        return Poll::Ready(Err(KcpError::DeadLink.into()));
+                    }
+                    inner.read_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                Err(e) => return Poll::Ready(Err(e.into())),
+            };
+            this.pending.resize(size, 0);
+            this.pos = 0;
+            let n = inner.kcp.ikcp_recv(&mut this.pending)?;
+            this.pending.truncate(n);
+            // 接收窗口可能重新打开了，需要尽快通知对方
+            this.session.notify.notify_one();
+        }
+
+        let n = min(buf.remaining(), this.pending.len() - this.pos);
+        buf.put_slice(&this.pending[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut inner = self.session.inner.lock().unwrap();
+        if inner.kcp.state() == LinkState::Dead {
+            return Poll::Ready(Err(KcpError::DeadLink.into()));
+        }
+        if inner.send_blocked() {
+            inner.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        // 消息模式下单条消息不能超过 max_message_size，流模式下不受限制
+        let buf = &buf[..min(buf.len(), inner.kcp.max_message_size())];
+        let n = inner.kcp.ikcp_send(buf)?;
+        self.session.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for KcpStream {
+    // 定时任务会继续运行，直到发送队列中的数据都被确认
+    fn drop(&mut self) {
+        self.session.inner.lock().unwrap().closed = true;
+        self.session.notify.notify_one();
+    }
+}
+
+// 在一个 udp socket 上接受 kcp 连接，按照 (对端地址, conv) 区分会话
+pub struct KcpListener {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(KcpStream, SocketAddr)>,
+    sessions: Arc<Sessions>,
+}
+
+impl KcpListener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, config: KcpConfig) -> io::Result<KcpListener> {
+        config.validate()?;
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        let (tx, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let sessions = Arc::new(Sessions {
+            map: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        });
+        tokio::spawn(accept_loop(socket, config, tx, sessions.clone()));
+        Ok(KcpListener {
+            local_addr,
+            incoming,
+            sessions,
+        })
+    }
+
+    pub async fn accept(&mut self) -> io::Result<(KcpStream, SocketAddr)> {
+        self.incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "listener closed"))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for KcpListener {
+    // 不再接受新的连接，已经接受的会话继续由收包任务提供输入
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.sessions.changed.notify_one();
+    }
+}
+
+async fn accept_loop(
+    socket: Arc<UdpSocket>,
+    config: KcpConfig,
+    tx: mpsc::Sender<(KcpStream, SocketAddr)>,
+    sessions: Arc<Sessions>,
+) {
+    let mut buf = vec![0; RECV_BUF_SIZE];
+    loop {
+        if tx.is_closed() && sessions.map.lock().unwrap().is_empty() {
+            break;
+        }
+        let (n, peer) = tokio::select! {
+            result = socket.recv_from(&mut buf) => match result {
+                Ok(x) => x,
+                // 例如 windows 上对端不可达时的 ECONNRESET，忽略即可
+                Err(_) => continue,
+            },
+            _ = sessions.changed.notified() => continue,
+        };
+        let pkt = &buf[..n];
+        let conv = match ikcp_getconv(pkt) {
+            Some(conv) => conv,
+            None => continue,
+        };
+
+        let existing = sessions.map.lock().unwrap().get(&(peer, conv)).cloned();
+        let session = match existing {
+            Some(session) => session,
+            None => {
+                // 只有合法的 PUSH 或 WASK 才能建立新会话，伪造的 ACK 等报文直接丢弃
+                match SegmentRef::decode(pkt) {
+                    Ok(seg) if matches!(seg.header.cmd, IKCP_CMD_PUSH | IKCP_CMD_WASK) => {}
+                    _ => continue,
+                }
+                // 先占用 backlog 中的位置，等待 accept 的会话不会超过 ACCEPT_BACKLOG
+                let permit = match tx.try_reserve() {
+                    Ok(permit) => permit,
+                    Err(_) => continue,
+                };
+                let kcp = match Kcp::with_config((), conv, &config) {
+                    Ok(kcp) => kcp,
+                    Err(_) => continue,
+                };
+                let session = Session::new(kcp, conv, peer, socket.clone());
+                sessions
+                    .map
+                    .lock()
+                    .unwrap()
+                    .insert((peer, conv), session.clone());
+                tokio::spawn(drive(session.clone(), Some(sessions.clone())));
+                permit.send((KcpStream::new(session.clone()), peer));
+                session
+            }
+        };
+        session.input(pkt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment::IKCP_CMD_ACK;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn echo_over_loopback() {
+        let config = KcpConfig::fastest().stream(true);
+        let mut listener = KcpListener::bind("127.0.0.1:0", config.clone())
+            .await
+            .unwrap();
+        let addr = listener.local_addr();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 100000];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            // 等待数据被确认之后再退出
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        });
+
+        let mut client = KcpStream::connect(addr, 7, &config).await.unwrap();
+        let data: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let mut echo = vec![0; data.len()];
+        tokio::time::timeout(Duration::from_secs(10), client.read_exact(&mut echo))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(echo, data);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_outlive_listener() {
+        let config = KcpConfig::fastest();
+        let mut listener = KcpListener::bind("127.0.0.1:0", config.clone())
+            .await
+            .unwrap();
+        let mut client = KcpStream::connect(listener.local_addr(), 9, &config)
+            .await
+            .unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let sessions = listener.sessions.clone();
+        drop(listener);
+
+        // listener 被 drop 之后已经接受的连接仍然可以收发数据
+        let timeout = Duration::from_secs(5);
+        let mut buf = [0; 5];
+        tokio::time::timeout(timeout, stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        client.write_all(b"world").await.unwrap();
+        tokio::time::timeout(timeout, stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"world");
+        stream.write_all(b"bye").await.unwrap();
+        let mut bye = [0; 3];
+        tokio::time::timeout(timeout, client.read_exact(&mut bye))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&bye, b"bye");
+
+        // 所有会话结束之后收包任务退出，释放共享的会话表
+        drop(stream);
+        let start = Instant::now();
+        while Arc::strong_count(&sessions) > 1 {
+            assert!(start.elapsed() < timeout, "accept loop did not exit");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(sessions.map.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_valid_requests_create_sessions() {
+        let listener = KcpListener::bind("127.0.0.1:0", KcpConfig::fastest())
+            .await
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut probe = Kcp::ickp_create((), 0);
+        probe.ikcp_send(b"x").unwrap();
+        probe.handle_timeout(0);
+        probe.handle_timeout(100);
+        let push = probe.poll_transmit().unwrap();
+
+        for conv in 0..ACCEPT_BACKLOG as u32 * 2 {
+            // 只有头部的报文、ACK 和截断的 PUSH 都不会建立会话
+            let mut pkt = push.to_vec();
+            pkt[..4].copy_from_slice(&conv.to_le_bytes());
+            let mut ack = pkt.clone();
+            ack[4] = IKCP_CMD_ACK;
+            for bad in [&pkt[..4], &ack[..], &pkt[..pkt.len() - 1]] {
+                socket.send_to(bad, listener.local_addr()).await.unwrap();
+            }
+            socket.send_to(&pkt, listener.local_addr()).await.unwrap();
+        }
+
+        // 没有被 accept 的会话不超过 ACCEPT_BACKLOG
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(listener.sessions.map.lock().unwrap().len(), ACCEPT_BACKLOG);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::error::KcpError;
+use bytes::BufMut;
+use std::iter::FusedIterator;
+
+pub const IKCP_CMD_PUSH: u8 = 81; // cmd: push data
+pub const IKCP_CMD_ACK: u8 = 82; // cmd: ack
+pub const IKCP_CMD_WASK: u8 = 83; // cmd: window probe (ask)
+pub const IKCP_CMD_WINS: u8 = 84; // cmd: window size (tell)
+pub const IKCP_OVERHEAD: u32 = 24; // segment 头部长度
+
+// segment 头部，24 字节，所有字段都是小端序，含义与 Kcp 内部的 Segment 一致
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SegmentHeader {
+    pub conv: u32,
+    pub cmd: u8,
+    pub frg: u8,
+    pub wnd: u16,
+    pub ts: u32,
+    pub sn: u32,
+    pub una: u32,
+    pub len: u32,
+}
+
+impl SegmentHeader {
+    // 解析 buf 开头的头部，不检查 cmd 和负载长度
+    pub fn decode(buf: &[u8]) -> Result<Self, KcpError> {
+        if buf.len() < IKCP_OVERHEAD as usize {
+            return Err(KcpError::Truncated);
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        Ok(SegmentHeader {
+            conv: u32_at(0),
+            cmd: buf[4],
+            frg: buf[5],
+            wnd: u16::from_le_bytes([buf[6], buf[7]]),
+            ts: u32_at(8),
+            sn: u32_at(12),
+            una: u32_at(16),
+            len: u32_at(20),
+        })
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u32_le(self.conv);
+        buf.put_u8(self.cmd);
+        buf.put_u8(self.frg);
+        buf.put_u16_le(self.wnd);
+        buf.put_u32_le(self.ts);
+        buf.put_u32_le(self.sn);
+        buf.put_u32_le(self.una);
+        buf.put_u32_le(self.len);
+    }
+}
+
+// 一个 segment，负载直接引用报文中的数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SegmentRef<'a> {
+    pub header: SegmentHeader,
+    pub data: &'a [u8],
+}
+
+impl<'a> SegmentRef<'a> {
+    // 解析 buf 开头的一个 segment，检查顺序与 C 版 ikcp_input 相同：先检查负载长度，再检查 cmd
+    pub fn decode(buf: &'a [u8]) -> Result<Self, KcpError> {
+        let header = SegmentHeader::decode(buf)?;
+        let payload = &buf[IKCP_OVERHEAD as usize..];
+        if payload.len() < header.len as usize {
+            return Err(KcpError::Truncated);
+        }
+        if !(IKCP_CMD_PUSH..=IKCP_CMD_WINS).contains(&header.cmd) {
+            return Err(KcpError::UnknownCommand(header.cmd));
+        }
+        Ok(SegmentRef {
+            header,
+            data: &payload[..header.len as usize],
+        })
+    }
+
+    // 编码后的长度
+    pub fn encoded_len(&self) -> usize {
+        IKCP_OVERHEAD as usize + self.data.len()
+    }
+
+    // 头部的 len 以 data 的实际长度为准
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        SegmentHeader {
+            len: self.data.len() as u32,
+            ..self.header
+        }
+        .encode(buf);
+        buf.put_slice(self.data);
+    }
+}
+
+// 依次解析一个报文中的所有 segment，规则与 ikcp_input 相同：
+// 报文不足一个头部时返回 Truncated，末尾不足一个头部的字节被忽略，出错之后不再返回任何 segment
+//
+// for seg in Segments::new(&datagram) {
+//     let seg = seg?;
+//     println!("cmd={} sn={} len={}", seg.header.cmd, seg.header.sn, seg.data.len());
+// }
+#[derive(Debug, Clone)]
+pub struct Segments<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Segments<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Segments {
+            buf,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    // 已经成功解析的字节数
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Result<SegmentRef<'a>, KcpError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let rest = &self.buf[self.offset..];
+        if rest.len() < IKCP_OVERHEAD as usize {
+            self.done = true;
+            return if self.offset == 0 {
+                Some(Err(KcpError::Truncated))
+            } else {
+                None
+            };
+        }
+        match SegmentRef::decode(rest) {
+            Ok(seg) => {
+                self.offset += seg.encoded_len();
+                Some(Ok(seg))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl FusedIterator for Segments<'_> {}
+
+// 不做任何校验地读取报文中第一个 segment 的头部，报文不足一个头部时返回 None，
+// 与 ikcp_getconv 一样用于在创建会话之前分流报文
+pub fn ikcp_peek_header(buf: &[u8]) -> Option<SegmentHeader> {
+    SegmentHeader::decode(buf).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp::Kcp;
+    use bytes::BytesMut;
+
+    fn push(sn: u32, data: &[u8]) -> SegmentRef<'_> {
+        SegmentRef {
+            header: SegmentHeader {
+                conv: 1,
+                cmd: IKCP_CMD_PUSH,
+                wnd: 128,
+                sn,
+                len: data.len() as u32,
+                ..Default::default()
+            },
+            data,
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = SegmentHeader {
+            conv: 0x11223344,
+            cmd: IKCP_CMD_ACK,
+            frg: 2,
+            wnd: 0x1234,
+            ts: 0xaabbccdd,
+            sn: 7,
+            una: 5,
+            len: 0,
+        };
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf);
+        assert_eq!(
+            &buf[..],
+            &[
+                0x44, 0x33, 0x22, 0x11, 82, 2, 0x34, 0x12, 0xdd, 0xcc, 0xbb, 0xaa, 7, 0, 0, 0, 5,
+                0, 0, 0, 0, 0, 0, 0
+            ]
+        );
+        assert_eq!(SegmentHeader::decode(&buf).unwrap(), header);
+        assert_eq!(ikcp_peek_header(&buf), Some(header));
+        assert_eq!(ikcp_peek_header(&buf[..23]), None);
+    }
+
+    #[test]
+    fn iterates_segments_in_datagram() {
+        let mut buf = BytesMut::new();
+        push(1, b"hello").encode(&mut buf);
+        push(2, b"").encode(&mut buf);
+        push(3, b"world").encode(&mut buf);
+        // 末尾不足一个头部的字节被忽略
+        buf.extend_from_slice(&[0; 10]);
+
+        let mut segments = Segments::new(&buf);
+        let segs: Vec<SegmentRef> = segments.by_ref().map(Result::unwrap).collect();
+        assert_eq!(
+            segs,
+            vec![push(1, b"hello"), push(2, b""), push(3, b"world")]
+        );
+        assert_eq!(segments.offset(), buf.len() - 10);
+
+        // 解析结果与 Kcp 收到的一致
+        let mut kcp = Kcp::ickp_create((), 1);
+        assert_eq!(kcp.ikcp_input(&buf).unwrap(), segments.offset());
+        assert_eq!(kcp.snapshot().rcv_buf, 3);
+    }
+
+    #[test]
+    fn reports_validation_errors() {
+        assert!(matches!(
+            Segments::new(&[0; 23]).collect::<Vec<_>>()[..],
+            [Err(KcpError::Truncated)]
+        ));
+        assert_eq!(Segments::new(&[]).count(), 1);
+
+        // 负载长度超出报文
+        let mut buf = BytesMut::new();
+        push(1, b"hello").encode(&mut buf);
+        let mut truncated = buf.clone();
+        truncated.truncate(buf.len() - 1);
+        let mut segments = Segments::new(&truncated);
+        assert!(matches!(segments.next(), Some(Err(KcpError::Truncated))));
+        assert!(segments.next().is_none());
+
+        // encode 按 data 的实际长度写入 len
+        let mut seg = push(1, b"hello");
+        seg.header.len = 100;
+        let mut encoded = BytesMut::new();
+        seg.encode(&mut encoded);
+        assert_eq!(encoded, buf);
+
+        // 第一个 segment 正常，第二个 cmd 未知，之后停止
+        let mut seg = push(2, b"x");
+        seg.header.cmd = 99;
+        seg.encode(&mut buf);
+        push(3, b"y").encode(&mut buf);
+        let results: Vec<_> = Segments::new(&buf).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(KcpError::UnknownCommand(99))));
+    }
+}
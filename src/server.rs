@@ -0,0 +1,385 @@
+use crate::kcp::{ikcp_getconv, Kcp};
+use crate::segment::{SegmentRef, IKCP_CMD_PUSH, IKCP_CMD_WASK};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+// 单个 udp 报文的最大长度
+const RECV_BUF_SIZE: usize = 65536;
+
+// 没有任何会话时 poll 的最长等待时间
+const IDLE_WAIT: u64 = 1000;
+
+type Key = (SocketAddr, u32);
+
+struct Session {
+    kcp: Kcp<()>,
+    // 已经放入定时器堆中的下一次 update 时间，堆中时间不同的条目已经过期
+    deadline: u64,
+    // 已经放入 readable 队列
+    readable: bool,
+}
+
+// 在一个 udp socket 上同步地管理多个 kcp 会话，按照 (对端地址, conv) 区分
+//
+// 收到未知会话的合法 PUSH 或 WASK 报文时调用 accept 回调，回调返回 Some(kcp) 表示接受这个会话，
+// 返回的 kcp 的 conv 和报文中的 conv 不一致时丢弃这个会话。
+// 所有会话共用一个按 ikcp_check 排序的定时器堆，poll() 会一直等待到最早的
+// ikcp_check 时间或者有报文到达。有完整消息可读的会话放入队列，由 pop_readable() 取出，
+// 修改会话（例如 ikcp_send）需要通过 session_mut()，这样定时器才会重新检查这个会话。
+//
+// let mut server = KcpServer::bind("0.0.0.0:7070", |_peer, conv| {
+//     Kcp::with_config((), conv, &KcpConfig::fast()).ok()
+// })?;
+// loop {
+//     server.poll()?;
+//     while let Some((peer, conv)) = server.pop_readable() {
+//         let kcp = server.session_mut(peer, conv).unwrap();
+//         ...
+//     }
+// }
+pub struct KcpServer<F> {
+    socket: UdpSocket,
+    sessions: HashMap<Key, Session>,
+    timers: BinaryHeap<Reverse<(u64, Key)>>,
+    // 有完整消息可读的会话
+    readable: VecDeque<Key>,
+    accept: F,
+    epoch: Instant,
+    read_timeout: Option<Duration>,
+    buf: Vec<u8>,
+}
+
+impl<F> KcpServer<F>
+where
+    F: FnMut(SocketAddr, u32) -> Option<Kcp<()>>,
+{
+    pub fn bind<A: ToSocketAddrs>(addr: A, accept: F) -> io::Result<Self> {
+        Self::from_socket(UdpSocket::bind(addr)?, accept)
+    }
+
+    // socket 会被设置为阻塞模式，poll 通过读超时等待定时器
+    pub fn from_socket(socket: UdpSocket, accept: F) -> io::Result<Self> {
+        socket.set_nonblocking(false)?;
+        Ok(KcpServer {
+            socket,
+            sessions: HashMap::new(),
+            timers: BinaryHeap::new(),
+            readable: VecDeque::new(),
+            accept,
+            epoch: Instant::now(),
+            read_timeout: None,
+            buf: vec![0; RECV_BUF_SIZE],
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // 所有会话使用的毫秒时钟
+    pub fn current(&self) -> u32 {
+        self.clock() as u32
+    }
+
+    // 定时器堆使用不会回绕的 64 位时钟
+    fn clock(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    // 等待到下一个会话需要 update 或者有报文到达，然后处理收到的报文、
+    // 更新到期的会话并发送它们生成的报文。收到的报文来自新会话时返回这个会话。
+    pub fn poll(&mut self) -> io::Result<Option<Key>> {
+        let now = self.clock();
+        // 0 表示阻塞，至少等待 1ms
+        let wait = Duration::from_millis(self.next_timeout(now).saturating_sub(now).max(1));
+        // 只在等待时间变化时才修改 socket 的读超时
+        if self.read_timeout != Some(wait) {
+            self.socket.set_read_timeout(Some(wait))?;
+            self.read_timeout = Some(wait);
+        }
+
+        let mut accepted = None;
+        match self.socket.recv_from(&mut self.buf) {
+            Ok((n, peer)) => accepted = self.input(n, peer),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+
+        self.update()?;
+        Ok(accepted)
+    }
+
+    // 更新所有到期的会话并发送待发送的报文
+    pub fn update(&mut self) -> io::Result<()> {
+        let now = self.clock();
+        while let Some(&Reverse((deadline, key))) = self.timers.peek() {
+            if deadline > now {
+                break;
+            }
+            self.timers.pop();
+            match self.sessions.get(&key) {
+                Some(session) if session.deadline == deadline => {}
+                // 会话已经删除或者重新排过期
+                _ => continue,
+            }
+            self.flush(key, now);
+        }
+        Ok(())
+    }
+
+    // 更新一个会话，发送它的报文，然后按照新的 ikcp_check 重新放入定时器堆
+    fn flush(&mut self, key: Key, now: u64) {
+        let Some(session) = self.sessions.get_mut(&key) else {
+            return;
+        };
+        let kcp = &mut session.kcp;
+        let current = now as u32;
+        if (kcp.poll_timeout().wrapping_sub(current) as i32) <= 0 {
+            kcp.handle_timeout(current);
+        }
+        while let Some(pkt) = kcp.peek_transmit() {
+            match self.socket.send_to(pkt, key.0) {
+                Ok(_) => {}
+                // socket 暂时不可写，报文留在 kcp 的队列中下次再发
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // 其它发送失败等同于丢包，由 kcp 负责重传
+                Err(_) => {}
+            }
+            kcp.poll_transmit();
+        }
+
+        let wait = kcp.poll_timeout().wrapping_sub(current) as i32;
+        let deadline = now + wait.max(0) as u64;
+        session.deadline = deadline;
+        self.timers.push(Reverse((deadline, key)));
+
+        // 过期条目太多时重建定时器堆
+        if self.timers.len() > self.sessions.len() * 2 + 64 {
+            self.timers = self
+                .sessions
+                .iter()
+                .map(|(key, session)| Reverse((session.deadline, *key)))
+                .collect();
+        }
+    }
+
+    // 会话被外部修改过（例如调用了 ikcp_send），下一次 update 时重新检查
+    fn touch(&mut self, key: Key) {
+        let now = self.clock();
+        if let Some(session) = self.sessions.get_mut(&key) {
+            if session.deadline > now {
+                session.deadline = now;
+                self.timers.push(Reverse((now, key)));
+            }
+        }
+    }
+
+    fn input(&mut self, n: usize, peer: SocketAddr) -> Option<Key> {
+        let pkt = &self.buf[..n];
+        let conv = ikcp_getconv(pkt)?;
+        let key = (peer, conv);
+        let mut accepted = None;
+        if !self.sessions.contains_key(&key) {
+            // 只有合法的 PUSH 或 WASK 才能建立新会话，伪造的 ACK 等报文直接丢弃
+            match SegmentRef::decode(pkt) {
+                Ok(seg) if matches!(seg.header.cmd, IKCP_CMD_PUSH | IKCP_CMD_WASK) => {}
+                _ => return None,
+            }
+            let kcp = (self.accept)(peer, conv)?;
+            // 回调返回的 conv 不一致时这个会话永远收不到报文，直接丢弃
+            if kcp.conv() != conv {
+                return None;
+            }
+            self.sessions.insert(
+                key,
+                Session {
+                    kcp,
+                    deadline: u64::MAX,
+                    readable: false,
+                },
+            );
+            accepted = Some(key);
+        }
+        let session = self.sessions.get_mut(&key).unwrap();
+        // 解析失败的报文直接丢弃
+        let _ = session.kcp.ikcp_input(pkt);
+        if !session.readable && session.kcp.ikcp_peeksize().is_ok() {
+            session.readable = true;
+            self.readable.push_back(key);
+        }
+        self.touch(key);
+        accepted
+    }
+
+    // 取出一个有完整消息可读的会话，取出后应当读完它的所有消息，
+    // 否则要等到这个会话收到下一个报文时才会再次放入队列
+    pub fn pop_readable(&mut self) -> Option<Key> {
+        while let Some(key) = self.readable.pop_front() {
+            if let Some(session) = self.sessions.get_mut(&key) {
+                session.readable = false;
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    // 所有会话中最早需要 update 的时间
+    fn next_timeout(&mut self, now: u64) -> u64 {
+        while let Some(&Reverse((deadline, key))) = self.timers.peek() {
+            match self.sessions.get(&key) {
+                Some(session) if session.deadline == deadline => return deadline,
+                _ => {
+                    self.timers.pop();
+                }
+            }
+        }
+        now + IDLE_WAIT
+    }
+
+    pub fn session(&self, peer: SocketAddr, conv: u32) -> Option<&Kcp<()>> {
+        self.sessions.get(&(peer, conv)).map(|session| &session.kcp)
+    }
+
+    pub fn session_mut(&mut self, peer: SocketAddr, conv: u32) -> Option<&mut Kcp<()>> {
+        self.touch((peer, conv));
+        self.sessions
+            .get_mut(&(peer, conv))
+            .map(|session| &mut session.kcp)
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = (SocketAddr, u32, &Kcp<()>)> {
+        self.sessions
+            .iter()
+            .map(|((peer, conv), session)| (*peer, *conv, &session.kcp))
+    }
+
+    pub fn remove(&mut self, peer: SocketAddr, conv: u32) -> Option<Kcp<()>> {
+        self.sessions
+            .remove(&(peer, conv))
+            .map(|session| session.kcp)
+    }
+
+    // 删除满足条件的会话，例如已经断开的连接
+    pub fn retain(&mut self, mut f: impl FnMut(SocketAddr, u32, &mut Kcp<()>) -> bool) {
+        self.sessions
+            .retain(|(peer, conv), session| f(*peer, *conv, &mut session.kcp));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment::IKCP_CMD_ACK;
+
+    #[test]
+    fn routes_datagrams_by_peer_and_conv() {
+        let mut server = KcpServer::bind("127.0.0.1:0", |_, conv| {
+            // 只接受偶数 conv
+            if conv % 2 == 0 {
+                Some(Kcp::ickp_create((), conv))
+            } else {
+                None
+            }
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = socket.local_addr().unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let mut clients: Vec<Kcp<()>> = [2, 3, 4]
+            .iter()
+            .map(|&conv| Kcp::ickp_create((), conv))
+            .collect();
+        for kcp in clients.iter_mut() {
+            kcp.ikcp_nodelay(true, 10, 2, true);
+            kcp.ikcp_send(format!("conv {}", kcp.conv()).as_bytes())
+                .unwrap();
+        }
+
+        let start = Instant::now();
+        let mut received = Vec::new();
+        let mut buf = [0; 64];
+        while received.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            let now = start.elapsed().as_millis() as u32;
+            for kcp in clients.iter_mut() {
+                kcp.handle_timeout(now);
+                while let Some(pkt) = kcp.poll_transmit() {
+                    socket.send_to(&pkt, addr).unwrap();
+                }
+            }
+            server.poll().unwrap();
+            while let Ok((n, _)) = socket.recv_from(&mut buf) {
+                let conv = ikcp_getconv(&buf[..n]).unwrap();
+                let kcp = clients.iter_mut().find(|k| k.conv() == conv);
+                kcp.unwrap().ikcp_input(&buf[..n]).unwrap();
+            }
+            while let Some((peer, conv)) = server.pop_readable() {
+                assert_eq!(peer, client_addr);
+                let kcp = server.session_mut(peer, conv).unwrap();
+                while let Ok(n) = kcp.ikcp_recv(&mut buf) {
+                    received.push((conv, String::from_utf8(buf[..n].to_vec()).unwrap()));
+                }
+            }
+        }
+
+        received.sort();
+        assert_eq!(
+            received,
+            vec![(2, "conv 2".to_string()), (4, "conv 4".to_string())]
+        );
+        assert_eq!(server.len(), 2);
+        assert!(server.session(client_addr, 3).is_none());
+    }
+
+    #[test]
+    fn only_valid_requests_create_sessions() {
+        // 回调返回的 conv 不一致时丢弃会话，不会 panic
+        let mut server = KcpServer::bind("127.0.0.1:0", |_, conv| {
+            Some(Kcp::ickp_create((), conv + 1))
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut kcp = Kcp::ickp_create((), 7);
+        kcp.ikcp_send(b"hello").unwrap();
+        kcp.handle_timeout(0);
+        kcp.handle_timeout(100);
+        let push = kcp.poll_transmit().unwrap();
+        socket.send_to(&push, addr).unwrap();
+        assert_eq!(server.poll().unwrap(), None);
+        assert!(server.is_empty());
+
+        // 只有可读的 conv 或者伪造的 ACK 不会调用 accept
+        let mut server = KcpServer::bind("127.0.0.1:0", |_, conv| -> Option<Kcp<()>> {
+            panic!("unexpected accept for conv {}", conv)
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        socket.send_to(&[7, 0, 0, 0, 0xff], addr).unwrap();
+        let mut ack = push.to_vec();
+        ack[4] = IKCP_CMD_ACK;
+        ack.truncate(24);
+        ack[20..24].copy_from_slice(&0u32.to_le_bytes());
+        socket.send_to(&ack, addr).unwrap();
+        assert_eq!(server.poll().unwrap(), None);
+        assert_eq!(server.poll().unwrap(), None);
+        assert!(server.is_empty());
+    }
+}
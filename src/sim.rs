@@ -0,0 +1,542 @@
+// 确定性的网络模拟，对应 C 版 test.cpp 中的 LatencySimulator
+//
+// 两个 sans-IO 的 Kcp 通过内存中的单向链路相连，链路可以配置丢包、时延、抖动、
+// 乱序、重复和带宽限制。所有随机数来自固定的种子，时间是虚拟时钟，
+// 同样的参数每次运行的结果都完全相同。
+//
+// let link = LinkConfig::default().loss(0.1).delay(30).jitter(20);
+// let mut sim = Simulator::new(Kcp::ickp_create((), 1), Kcp::ickp_create((), 1), link, 42);
+// sim.a_mut().ikcp_send(b"hello")?;
+// sim.run_until(10000, |sim| sim.b().ikcp_peeksize().is_ok());
+use crate::kcp::{diff, Kcp};
+use bytes::Bytes;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// splitmix64，足够用于模拟并且不依赖外部 crate
+#[derive(Debug, Clone)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // [0, 1) 之间的均匀分布
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // 以概率 p 返回 true
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+
+    // [0, n] 之间的整数
+    pub fn below(&mut self, n: u32) -> u32 {
+        (self.next_u64() % (n as u64 + 1)) as u32
+    }
+}
+
+// 单向链路的参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkConfig {
+    pub(crate) loss: f64,
+    pub(crate) delay: u32,
+    pub(crate) jitter: u32,
+    pub(crate) reorder: f64,
+    pub(crate) duplicate: f64,
+    pub(crate) bandwidth: Option<u64>,
+    pub(crate) queue_delay: u32,
+}
+
+impl Default for LinkConfig {
+    // 没有丢包、时延和带宽限制的理想链路
+    fn default() -> Self {
+        LinkConfig {
+            loss: 0.0,
+            delay: 0,
+            jitter: 0,
+            reorder: 0.0,
+            duplicate: 0.0,
+            bandwidth: None,
+            queue_delay: 1000,
+        }
+    }
+}
+
+impl LinkConfig {
+    // 随机丢包的概率，0.0 到 1.0
+    pub fn loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    // 单向的固定时延，毫秒
+    pub fn delay(mut self, delay: u32) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    // 在固定时延上随机增加 0 到 jitter 毫秒，抖动本身不会打乱报文的顺序
+    pub fn jitter(mut self, jitter: u32) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    // 报文被额外延迟 delay + jitter 毫秒从而乱序的概率
+    pub fn reorder(mut self, reorder: f64) -> Self {
+        self.reorder = reorder;
+        self
+    }
+
+    // 报文被重复发送一次的概率
+    pub fn duplicate(mut self, duplicate: f64) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    // 瓶颈带宽，字节/秒
+    pub fn bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(bytes_per_sec);
+        self
+    }
+
+    // 有带宽限制时，排队时间超过这个值的报文被丢弃，毫秒
+    pub fn queue_delay(mut self, queue_delay: u32) -> Self {
+        self.queue_delay = queue_delay;
+        self
+    }
+}
+
+// 链路上的计数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub sent_pkts: u64,
+    pub sent_bytes: u64,
+    pub delivered_pkts: u64,
+    pub delivered_bytes: u64,
+    // 随机丢包和队列溢出丢弃的报文
+    pub lost_pkts: u64,
+    pub overflow_pkts: u64,
+    pub reordered_pkts: u64,
+    pub duplicated_pkts: u64,
+}
+
+// 单向链路
+#[derive(Debug)]
+pub struct SimLink {
+    config: LinkConfig,
+    rng: SimRng,
+
+    // (到达时间, 序号, 报文)，序号保证同一时间到达的报文按发送顺序交付
+    queue: BinaryHeap<Reverse<(u32, u64, Bytes)>>,
+    seq: u64,
+
+    // 按顺序交付的报文中最晚的到达时间
+    last_arrival: u32,
+
+    // 瓶颈链路空闲的时间，微秒
+    busy_until: u64,
+
+    stats: LinkStats,
+}
+
+impl SimLink {
+    pub fn new(config: LinkConfig, seed: u64) -> Self {
+        SimLink {
+            config,
+            rng: SimRng::new(seed),
+            queue: BinaryHeap::new(),
+            seq: 0,
+            last_arrival: 0,
+            busy_until: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &LinkConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &LinkStats {
+        &self.stats
+    }
+
+    // 链路中还没有交付的报文数
+    pub fn in_flight(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn send(&mut self, now: u32, pkt: Bytes) {
+        self.stats.sent_pkts += 1;
+        self.stats.sent_bytes += pkt.len() as u64;
+        if self.rng.chance(self.config.loss) {
+            self.stats.lost_pkts += 1;
+            return;
+        }
+        if self.rng.chance(self.config.duplicate) {
+            self.stats.duplicated_pkts += 1;
+            self.enqueue(now, pkt.clone());
+        }
+        self.enqueue(now, pkt);
+    }
+
+    fn enqueue(&mut self, now: u32, pkt: Bytes) {
+        let mut depart = now;
+        if let Some(bw) = self.config.bandwidth {
+            let now_us = now as u64 * 1000;
+            let start = self.busy_until.max(now_us);
+            if start - now_us > self.config.queue_delay as u64 * 1000 {
+                self.stats.overflow_pkts += 1;
+                return;
+            }
+            self.busy_until = start + pkt.len() as u64 * 1_000_000 / bw.max(1);
+            depart = self.busy_until.div_ceil(1000) as u32;
+        }
+
+        let mut arrival = depart
+            .wrapping_add(self.config.delay)
+            .wrapping_add(self.rng.below(self.config.jitter));
+        if self.rng.chance(self.config.reorder) {
+            self.stats.reordered_pkts += 1;
+            arrival = arrival
+                .wrapping_add(self.config.delay)
+                .wrapping_add(self.config.jitter)
+                .wrapping_add(1);
+        } else {
+            // 抖动不改变顺序
+            if self.seq > 0 && diff(arrival, self.last_arrival) < 0 {
+                arrival = self.last_arrival;
+            }
+            self.last_arrival = arrival;
+        }
+        self.queue.push(Reverse((arrival, self.seq, pkt)));
+        self.seq += 1;
+    }
+
+    // 下一个报文的到达时间
+    pub fn next_arrival(&self) -> Option<u32> {
+        self.queue.peek().map(|Reverse((ts, _, _))| *ts)
+    }
+
+    // 取出一个在 now 之前到达的报文
+    pub fn recv(&mut self, now: u32) -> Option<Bytes> {
+        match self.queue.peek() {
+            Some(Reverse((ts, _, _))) if diff(now, *ts) >= 0 => {
+                let Reverse((_, _, pkt)) = self.queue.pop().unwrap();
+                self.stats.delivered_pkts += 1;
+                self.stats.delivered_bytes += pkt.len() as u64;
+                Some(pkt)
+            }
+            _ => None,
+        }
+    }
+}
+
+// 通过两条 SimLink 连接的一对 Kcp，时间只在调用 advance/run_until 时前进
+pub struct Simulator {
+    a: Kcp<()>,
+    b: Kcp<()>,
+    a_to_b: SimLink,
+    b_to_a: SimLink,
+    now: u32,
+    // ikcp_input 返回错误的报文数
+    input_errors: u64,
+}
+
+impl Simulator {
+    // 两个方向使用相同参数、不同随机序列的链路
+    pub fn new(a: Kcp<()>, b: Kcp<()>, link: LinkConfig, seed: u64) -> Self {
+        Self::with_links(
+            a,
+            b,
+            SimLink::new(link.clone(), seed),
+            SimLink::new(link, seed ^ 0x5555_5555_5555_5555),
+        )
+    }
+
+    pub fn with_links(a: Kcp<()>, b: Kcp<()>, a_to_b: SimLink, b_to_a: SimLink) -> Self {
+        Simulator {
+            a,
+            b,
+            a_to_b,
+            b_to_a,
+            now: 0,
+            input_errors: 0,
+        }
+    }
+
+    // 当前的虚拟时间，毫秒
+    pub fn now(&self) -> u32 {
+        self.now
+    }
+
+    pub fn a(&self) -> &Kcp<()> {
+        &self.a
+    }
+
+    pub fn a_mut(&mut self) -> &mut Kcp<()> {
+        &mut self.a
+    }
+
+    pub fn b(&self) -> &Kcp<()> {
+        &self.b
+    }
+
+    pub fn b_mut(&mut self) -> &mut Kcp<()> {
+        &mut self.b
+    }
+
+    pub fn a_to_b(&self) -> &SimLink {
+        &self.a_to_b
+    }
+
+    pub fn b_to_a(&self) -> &SimLink {
+        &self.b_to_a
+    }
+
+    pub fn input_errors(&self) -> u64 {
+        self.input_errors
+    }
+
+    // 在当前时间处理到期的事件：更新 kcp、发送报文并交付已经到达的报文
+    fn tick(&mut self) {
+        let now = self.now;
+        loop {
+            self.a.handle_timeout(now);
+            self.b.handle_timeout(now);
+            while let Some(pkt) = self.a.poll_transmit() {
+                self.a_to_b.send(now, pkt);
+            }
+            while let Some(pkt) = self.b.poll_transmit() {
+                self.b_to_a.send(now, pkt);
+            }
+
+            let mut delivered = false;
+            while let Some(pkt) = self.a_to_b.recv(now) {
+                delivered = true;
+                if self.b.input_bytes(pkt).is_err() {
+                    self.input_errors += 1;
+                }
+            }
+            while let Some(pkt) = self.b_to_a.recv(now) {
+                delivered = true;
+                if self.a.input_bytes(pkt).is_err() {
+                    self.input_errors += 1;
+                }
+            }
+            // 零时延的链路上报文可能在同一时刻到达，继续处理直到没有新的报文
+            if !delivered {
+                break;
+            }
+        }
+    }
+
+    // 下一个需要处理的时间点
+    fn next_event(&self) -> u32 {
+        let mut next = self.a.poll_timeout();
+        for ts in [
+            Some(self.b.poll_timeout()),
+            self.a_to_b.next_arrival(),
+            self.b_to_a.next_arrival(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if diff(ts, next) < 0 {
+                next = ts;
+            }
+        }
+        next
+    }
+
+    // 前进 ms 毫秒，中间只在有事件的时间点处理
+    pub fn advance(&mut self, ms: u32) {
+        let end = self.now.wrapping_add(ms);
+        self.tick();
+        loop {
+            let next = self.next_event();
+            if diff(next, end) > 0 {
+                break;
+            }
+            self.now = if diff(next, self.now) > 0 {
+                next
+            } else {
+                self.now.wrapping_add(1)
+            };
+            self.tick();
+        }
+        self.now = end;
+        self.tick();
+    }
+
+    // 每毫秒检查一次 done，返回 true 表示在 max 毫秒之内满足了条件
+    pub fn run_until(&mut self, max: u32, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        for _ in 0..max {
+            if done(self) {
+                return true;
+            }
+            self.advance(1);
+        }
+        done(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkts(link: &mut SimLink, n: u8) -> Vec<(u32, u8)> {
+        for i in 0..n {
+            link.send(0, Bytes::from(vec![i; 100]));
+        }
+        let mut out = Vec::new();
+        for now in 0..10000 {
+            while let Some(pkt) = link.recv(now) {
+                out.push((now, pkt[0]));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn link_is_deterministic() {
+        let config = LinkConfig::default()
+            .loss(0.2)
+            .delay(20)
+            .jitter(30)
+            .reorder(0.1)
+            .duplicate(0.1);
+        let first = pkts(&mut SimLink::new(config.clone(), 7), 200);
+        let second = pkts(&mut SimLink::new(config.clone(), 7), 200);
+        let other = pkts(&mut SimLink::new(config, 8), 200);
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn link_impairments() {
+        // 丢包率
+        let mut link = SimLink::new(LinkConfig::default().loss(0.25), 1);
+        let n = pkts(&mut link, 200).len();
+        assert!((120..=180).contains(&n));
+        assert_eq!(link.stats().lost_pkts, 200 - n as u64);
+
+        // 时延和抖动，不乱序
+        let mut link = SimLink::new(LinkConfig::default().delay(50).jitter(20), 1);
+        let out = pkts(&mut link, 100);
+        assert!(out.iter().all(|&(ts, _)| (50..=70).contains(&ts)));
+        assert!(out.windows(2).all(|w| w[0].1 < w[1].1));
+
+        // 乱序
+        let mut link = SimLink::new(LinkConfig::default().delay(10).reorder(0.3), 1);
+        let out = pkts(&mut link, 100);
+        assert_eq!(out.len(), 100);
+        assert!(out.windows(2).any(|w| w[0].1 > w[1].1));
+
+        // 重复
+        let mut link = SimLink::new(LinkConfig::default().duplicate(0.5), 1);
+        let out = pkts(&mut link, 100);
+        assert_eq!(out.len() as u64, 100 + link.stats().duplicated_pkts);
+        assert!(out.len() > 120);
+
+        // 带宽 100KB/s，每个报文 1ms，队列最多 20ms
+        let config = LinkConfig::default().bandwidth(100_000).queue_delay(20);
+        let mut link = SimLink::new(config, 1);
+        let out = pkts(&mut link, 50);
+        assert_eq!(out.len(), 21);
+        assert_eq!(out.last().unwrap().0, 21);
+        assert_eq!(link.stats().overflow_pkts, 29);
+    }
+
+    // 与 test.cpp 相同的场景：10% 丢包，单向时延 30 到 60ms，a 发出的消息由 b 原样返回
+    fn echo_rtt(nodelay: bool, interval: u32, resend: u32, nc: bool) -> (u32, u32) {
+        let mut a = Kcp::ickp_create((), 0x11223344);
+        let mut b = Kcp::ickp_create((), 0x11223344);
+        for kcp in [&mut a, &mut b] {
+            kcp.ikcp_wndsize(128, 128);
+            kcp.ikcp_nodelay(nodelay, interval, resend, nc);
+        }
+        let link = LinkConfig::default().loss(0.1).delay(30).jitter(30);
+        let mut sim = Simulator::new(a, b, link, 0x1234);
+
+        let mut next_send = 0;
+        let mut sent = 0u32;
+        let mut received = 0u32;
+        let mut total_rtt = 0;
+        let mut max_rtt = 0;
+        let mut buf = [0; 8];
+        while received < 500 {
+            let now = sim.now();
+            if sent < 500 && diff(now, next_send) >= 0 {
+                let mut msg = [0; 8];
+                msg[..4].copy_from_slice(&sent.to_le_bytes());
+                msg[4..].copy_from_slice(&now.to_le_bytes());
+                sim.a_mut().ikcp_send(&msg).unwrap();
+                sent += 1;
+                next_send = now + 20;
+            }
+            while let Ok(n) = sim.b_mut().ikcp_recv(&mut buf) {
+                sim.b_mut().ikcp_send(&buf[..n]).unwrap();
+            }
+            while let Ok(n) = sim.a_mut().ikcp_recv(&mut buf) {
+                assert_eq!(n, 8);
+                let sn = u32::from_le_bytes(buf[..4].try_into().unwrap());
+                let ts = u32::from_le_bytes(buf[4..].try_into().unwrap());
+                assert_eq!(sn, received);
+                received += 1;
+                let rtt = now - ts;
+                total_rtt += rtt;
+                max_rtt = max_rtt.max(rtt);
+            }
+            sim.advance(1);
+            assert!(now < 600_000);
+        }
+        assert_eq!(sim.input_errors(), 0);
+        (total_rtt / received, max_rtt)
+    }
+
+    #[test]
+    fn modes_over_lossy_link() {
+        let (normal_avg, _) = echo_rtt(false, 10, 0, false);
+        let (fast_avg, fast_max) = echo_rtt(false, 10, 2, true);
+        let (fastest_avg, fastest_max) = echo_rtt(true, 10, 2, true);
+        // 与 test.cpp 的结论一致：越快的模式平均 rtt 越小
+        assert!(fastest_avg <= fast_avg && fast_avg < normal_avg);
+        assert!(fastest_max <= fast_max);
+
+        // 同样的参数结果完全相同
+        assert_eq!(echo_rtt(true, 10, 2, true), (fastest_avg, fastest_max));
+    }
+
+    #[test]
+    fn advance_skips_idle_time() {
+        let mut sim = Simulator::new(
+            Kcp::ickp_create((), 1),
+            Kcp::ickp_create((), 1),
+            LinkConfig::default().delay(5),
+            1,
+        );
+        sim.a_mut().ikcp_nodelay(true, 10, 2, true);
+        sim.a_mut().ikcp_send(b"ping").unwrap();
+        assert!(sim.run_until(1000, |sim| sim.b().ikcp_peeksize().is_ok()));
+        assert!(sim.now() >= 5 && sim.now() < 50);
+
+        let now = sim.now();
+        sim.advance(60_000);
+        assert_eq!(sim.now(), now + 60_000);
+        assert_eq!(sim.a().ikcp_waitsnd(), 0);
+        assert_eq!(sim.a_to_b().stats().delivered_pkts, 1);
+    }
+}
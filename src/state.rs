@@ -0,0 +1,43 @@
+use crate::kcp::LinkState;
+
+// Kcp::snapshot() 返回的只读状态，字段含义与 Kcp 内部同名字段一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KcpState {
+    // 平滑后的 rtt 及其变化量
+    pub rx_srtt: u32,
+    pub rx_rttval: u32,
+
+    // 当前的重传超时时间
+    pub rx_rto: u32,
+
+    // 拥塞窗口及其阈值
+    pub cwnd: u32,
+    pub ssthresh: u32,
+
+    // 远端接收窗口、本地发送窗口和接收窗口
+    pub rmt_wnd: u32,
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    pub rcv_nxt: u32,
+
+    // 各个队列中的 segment 数
+    pub snd_queue: usize,
+    pub snd_buf: usize,
+    pub rcv_queue: usize,
+    pub rcv_buf: usize,
+
+    // 等待发送的 ack 数
+    pub acks_pending: usize,
+
+    // 远端窗口为 0 时下一次发送窗口探测的时间戳以及当前的探测间隔，没有在探测时都为 0
+    pub ts_probe: u32,
+    pub probe_wait: u32,
+
+    // 超时重传的总次数
+    pub xmit: u32,
+
+    pub link_state: LinkState,
+}
@@ -0,0 +1,45 @@
+// 单个会话的累计流量和重传计数，可以通过 Kcp::reset_stats() 清零
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KcpStats {
+    // 成功写入 output 的报文数和字节数（包含 kcp 头部）
+    pub out_pkts: u64,
+    pub out_bytes: u64,
+
+    // 交给 ikcp_input 的报文数和字节数
+    pub in_pkts: u64,
+    pub in_bytes: u64,
+
+    // 发送的 IKCP_CMD_PUSH segment 数（包含重传）和负载字节数
+    pub out_data_segs: u64,
+    pub out_data_bytes: u64,
+
+    // 解析出的 segment 数，以及其中 IKCP_CMD_PUSH 的数量和负载字节数
+    pub in_segs: u64,
+    pub in_data_segs: u64,
+    pub in_data_bytes: u64,
+
+    // 超时重传（flush 中的 lost 分支）和快速重传（change 分支）的 segment 数
+    pub lost_segs: u64,
+    pub fast_retrans_segs: u64,
+
+    // 重复收到而被丢弃的 segment 数
+    pub repeat_segs: u64,
+
+    // 超出接收窗口而被丢弃的 segment 数
+    pub out_of_window_segs: u64,
+
+    pub acks_sent: u64,
+    pub acks_received: u64,
+
+    // 窗口探测（IKCP_CMD_WASK）和窗口通知（IKCP_CMD_WINS）
+    pub wask_sent: u64,
+    pub wask_received: u64,
+    pub wins_sent: u64,
+    pub wins_received: u64,
+
+    // ikcp_input 解析失败的报文数
+    pub input_errors: u64,
+
+    // Write 方式的 output 一直写入失败、output_queue 超出上限时丢弃的最旧报文数
+    pub output_dropped: u64,
+}
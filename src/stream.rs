@@ -0,0 +1,132 @@
+use crate::error::KcpError;
+use crate::kcp::Kcp;
+use std::cmp::min;
+use std::io::{self, Read, Write};
+
+// 流模式 kcp 的 std::io 适配器
+//
+// Read 在没有数据时返回 io::ErrorKind::WouldBlock，Write 只把数据放进发送队列。
+// KcpIo 自己不收发 udp 报文，调用者需要一个驱动循环：通过 get_mut() 把收到的报文
+// 交给 ikcp_input、按 ikcp_check 调用 ikcp_update，并在 WouldBlock 之后重试读取。
+// 因此 io::copy、read_to_end 这类遇到 WouldBlock 就返回错误的函数不能直接使用，
+// 需要自带收发和定时驱动的连接可以使用 tokio feature 下的 KcpStream。
+pub struct KcpIo<W: Write> {
+    kcp: Kcp<W>,
+
+    // 已经从 kcp 中取出但还没有被读走的数据
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl<W: Write> KcpIo<W> {
+    pub fn new(w: W, conv: u32) -> Self {
+        KcpIo {
+            kcp: Kcp::ickp_create_stream(w, conv),
+            pending: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // kcp 必须是流模式，消息模式下 Read 无法按字节流拆分消息
+    pub fn from_kcp(kcp: Kcp<W>) -> Result<Self, KcpError> {
+        if !kcp.is_stream() {
+            return Err(KcpError::InvalidConfig("KcpIo requires a stream mode kcp"));
+        }
+        Ok(KcpIo {
+            kcp,
+            pending: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub fn get_ref(&self) -> &Kcp<W> {
+        &self.kcp
+    }
+
+    pub fn get_mut(&mut self) -> &mut Kcp<W> {
+        &mut self.kcp
+    }
+
+    pub fn into_inner(self) -> Kcp<W> {
+        self.kcp
+    }
+}
+
+impl<W: Write> Read for KcpIo<W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.pos == self.pending.len() {
+            let size = self.kcp.ikcp_peeksize()? as usize;
+            // 缓冲区足够大时直接读到调用者的缓冲区中
+            if size <= buf.len() {
+                return Ok(self.kcp.ikcp_recv(buf)?);
+            }
+            self.pending.resize(size, 0);
+            self.pos = 0;
+            let n = self.kcp.ikcp_recv(&mut self.pending)?;
+            self.pending.truncate(n);
+        }
+
+        let n = min(buf.len(), self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for KcpIo<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.kcp.ikcp_send(buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.kcp.ikcp_flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::io::BufReader;
+
+    #[test]
+    fn pipe_bytes_through_kcp() {
+        let mut a = KcpIo::new(Vec::new(), 1);
+        let mut b = KcpIo::new(Vec::new(), 1);
+        a.get_mut().ikcp_nodelay(true, 10, 2, true);
+
+        a.write_all(b"first line\nsecond ").unwrap();
+        a.write_all(b"line\n").unwrap();
+        a.get_mut().ikcp_update(0).unwrap();
+
+        let packets = std::mem::take(a.get_mut().output_mut());
+        b.get_mut().ikcp_input(&packets).unwrap();
+
+        let mut reader = BufReader::with_capacity(4, b);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "first line\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "second line\n");
+
+        let err = reader.read(&mut [0; 4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn from_kcp_requires_stream_mode() {
+        assert!(matches!(
+            KcpIo::from_kcp(Kcp::ickp_create(Vec::new(), 1)),
+            Err(KcpError::InvalidConfig(_))
+        ));
+        assert!(KcpIo::from_kcp(Kcp::ickp_create_stream(Vec::new(), 1)).is_ok());
+    }
+}
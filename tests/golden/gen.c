@@ -0,0 +1,259 @@
+//=====================================================================
+//
+// gen.c - 用 C 版 ikcp 生成 tests/interop.rs 使用的 golden trace
+//
+// 需要 skywind3000/kcp 中带有 fastlimit 的 ikcp.c/ikcp.h，默认编译生成所有场景：
+// gcc -O2 -I <kcp> gen.c <kcp>/ikcp.c -o gen && ./gen
+//
+// 定义 IKCP_FASTACK_CONSERVE 编译时生成 <场景>_conserve.trace，对应 Kcp::set_fastack_conserve：
+// gcc -O2 -DIKCP_FASTACK_CONSERVE -I <kcp> gen.c <kcp>/ikcp.c -o gen_conserve && ./gen_conserve lossy_fast
+//
+// 注意有些第三方打包的 ikcp.c（例如 kcp-sys）在文件开头直接写了 #define IKCP_FASTACK_CONSERVE，
+// 生成默认的 trace 之前需要删掉这一行。
+//
+// 命令行参数为要生成的场景名，没有参数时生成全部场景。
+// 两个端点 a、b 通过模拟链路通信，a 发送消息，b 把收到的数据原样返回。
+// 每个端点上的调用和输出按顺序记录为一行：
+//
+//   <端点> <时间> update
+//   <端点> <时间> send <hex>
+//   <端点> <时间> input <返回值> <hex>
+//   <端点> <时间> output <hex>
+//   <端点> <时间> recv [hex]          没有 hex 表示 ikcp_recv 返回 < 0
+//
+//=====================================================================
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "ikcp.h"
+
+#ifdef IKCP_FASTACK_CONSERVE
+#define CONSERVE 1
+#define SUFFIX "_conserve"
+#else
+#define CONSERVE 0
+#define SUFFIX ""
+#endif
+
+typedef struct {
+	const char *name;
+	const char *desc;
+	int nodelay, interval, resend, nc;
+	int sndwnd, rcvwnd, mtu, stream;
+	int loss, corrupt;          // 百分比
+	int dmin, dmax;             // 单向时延，毫秒
+	int count, gap;             // a 每 gap 毫秒发送一条消息，共 count 条
+	int smin, smax;             // 消息长度范围
+	int hold;                   // b 在这个时间之前不读取数据
+} Scenario;
+
+static const Scenario scenarios[] = {
+	{ "basic", "default parameters, no loss, fragmented messages",
+	  0, 100, 0, 0, 32, 128, 1400, 0, 0, 0, 20, 20, 6, 200, 1, 3000, 0 },
+	{ "lossy_default", "test.cpp default mode: 10% loss, rtt 60~124ms",
+	  0, 10, 0, 0, 128, 128, 1400, 0, 10, 0, 30, 62, 300, 20, 8, 8, 0 },
+	{ "lossy_fast", "fast mode with 10% loss and 2% truncated datagrams",
+	  1, 10, 2, 1, 128, 128, 1400, 0, 10, 2, 30, 62, 80, 20, 1, 200, 0 },
+	{ "stream", "stream mode, mtu 600, 5% loss",
+	  1, 20, 2, 0, 64, 128, 600, 1, 5, 0, 20, 40, 60, 10, 1, 400, 0 },
+	{ "small_mtu", "mtu 100 with up to 27 fragments per message, 5% loss",
+	  0, 10, 2, 0, 64, 128, 100, 0, 5, 0, 10, 30, 6, 100, 500, 2000, 0 },
+	{ "zero_window", "receiver stops reading, window probing until it drains",
+	  1, 10, 0, 1, 256, 128, 1400, 0, 0, 0, 10, 10, 150, 5, 20, 20, 10000 },
+};
+
+static unsigned int rng = 1;
+
+static unsigned int rnd(void)
+{
+	rng ^= rng << 13;
+	rng ^= rng >> 17;
+	rng ^= rng << 5;
+	return rng;
+}
+
+#define MAX_PACKETS 65536
+
+typedef struct {
+	IUINT32 at;
+	int to;
+	int len;
+	char data[1600];
+} Packet;
+
+static Packet packets[MAX_PACKETS];
+static int npackets = 0;
+
+static FILE *out;
+static const Scenario *sc;
+static IUINT32 now;
+
+static void hex(const char *data, int len)
+{
+	int i;
+	for (i = 0; i < len; i++) fprintf(out, "%02x", (unsigned char)data[i]);
+}
+
+static void event(int ep, const char *op, const char *data, int len)
+{
+	fprintf(out, "%c %u %s", 'a' + ep, now, op);
+	if (data != NULL && len > 0) {
+		fputc(' ', out);
+		hex(data, len);
+	}
+	fputc('\n', out);
+}
+
+static int udp_output(const char *buf, int len, ikcpcb *kcp, void *user)
+{
+	int ep = (int)(size_t)user;
+	Packet *pkt;
+	event(ep, "output", buf, len);
+	if ((int)(rnd() % 100) < sc->loss) return 0;
+	if (npackets >= MAX_PACKETS) abort();
+	pkt = &packets[npackets++];
+	pkt->at = now + sc->dmin + rnd() % (sc->dmax - sc->dmin + 1);
+	pkt->to = 1 - ep;
+	pkt->len = len;
+	memcpy(pkt->data, buf, len);
+	// 截断一部分报文，测试错误处理
+	if ((int)(rnd() % 100) < sc->corrupt) {
+		pkt->len = rnd() % len;
+	}
+	return 0;
+}
+
+static void input(ikcpcb *kcp, int ep, const char *data, int len)
+{
+	int ret = ikcp_input(kcp, data, len);
+	fprintf(out, "%c %u input %d ", 'a' + ep, now, ret);
+	hex(data, len);
+	fputc('\n', out);
+}
+
+static int recv_all(ikcpcb *kcp, int ep, char *buf, int cap)
+{
+	int total = 0;
+	while (1) {
+		int n = ikcp_recv(kcp, buf + total, cap - total);
+		if (n < 0) {
+			event(ep, "recv", NULL, 0);
+			break;
+		}
+		event(ep, "recv", buf + total, n);
+		total += n;
+	}
+	return total;
+}
+
+static void run(const Scenario *s)
+{
+	static char buf[1 << 20];
+	char path[256];
+	ikcpcb *kcp[2];
+	IUINT32 next_update[2] = { 0, 0 };
+	long sent = 0, echoed = 0;
+	int index = 0, ep, i;
+
+	sc = s;
+	rng = 0x9e3779b9;
+	npackets = 0;
+	now = 0;
+
+	snprintf(path, sizeof(path), "%s" SUFFIX ".trace", s->name);
+	out = fopen(path, "w");
+	fprintf(out, "# %s%s\n", s->desc, CONSERVE ? ", IKCP_FASTACK_CONSERVE" : "");
+	fprintf(out, "config %d %d %d %d %d %d %d %d %d\n", s->nodelay, s->interval,
+		s->resend, s->nc, s->sndwnd, s->rcvwnd, s->mtu, s->stream, CONSERVE);
+
+	for (ep = 0; ep < 2; ep++) {
+		kcp[ep] = ikcp_create(0x11223344, (void*)(size_t)ep);
+		kcp[ep]->output = udp_output;
+		kcp[ep]->stream = s->stream;
+		ikcp_nodelay(kcp[ep], s->nodelay, s->interval, s->resend, s->nc);
+		ikcp_wndsize(kcp[ep], s->sndwnd, s->rcvwnd);
+		ikcp_setmtu(kcp[ep], s->mtu);
+	}
+
+	for (now = 0; now < 120000; now++) {
+		// a 按固定间隔发送消息
+		if (index < s->count && now >= (IUINT32)(index * s->gap)) {
+			int len = s->smin + (index * 7919) % (s->smax - s->smin + 1);
+			for (i = 0; i < len; i++) buf[i] = (char)(index * 31 + i);
+			if (len >= 4) memcpy(buf, &index, 4);
+			if (ikcp_send(kcp[0], buf, len) != len) abort();
+			event(0, "send", buf, len);
+			sent += len;
+			index++;
+		}
+
+		// 投递到达的报文，同一时刻到达的按发送顺序投递
+		for (i = 0; i < npackets; ) {
+			if (packets[i].at == now) {
+				Packet pkt = packets[i];
+				memmove(&packets[i], &packets[i + 1],
+					(npackets - i - 1) * sizeof(Packet));
+				npackets--;
+				input(kcp[pkt.to], pkt.to, pkt.data, pkt.len);
+				if (pkt.to == 0) {
+					echoed += recv_all(kcp[0], 0, buf, sizeof(buf));
+				}
+				else if (now >= (IUINT32)s->hold) {
+					int n = recv_all(kcp[1], 1, buf, sizeof(buf));
+					if (n > 0) {
+						if (ikcp_send(kcp[1], buf, n) != n) abort();
+						event(1, "send", buf, n);
+					}
+				}
+			}	else {
+				i++;
+			}
+		}
+
+		// b 恢复读取
+		if (s->hold > 0 && now == (IUINT32)s->hold) {
+			int n = recv_all(kcp[1], 1, buf, sizeof(buf));
+			if (n > 0) {
+				if (ikcp_send(kcp[1], buf, n) != n) abort();
+				event(1, "send", buf, n);
+			}
+		}
+
+		for (ep = 0; ep < 2; ep++) {
+			if (now >= next_update[ep]) {
+				event(ep, "update", NULL, 0);
+				ikcp_update(kcp[ep], now);
+				next_update[ep] = ikcp_check(kcp[ep], now);
+			}
+		}
+
+		if (index == s->count && echoed == sent && npackets == 0 &&
+			ikcp_waitsnd(kcp[0]) == 0 && ikcp_waitsnd(kcp[1]) == 0) {
+			break;
+		}
+	}
+
+	if (echoed != sent) {
+		fprintf(stderr, "%s: only %ld of %ld bytes echoed\n", s->name, echoed, sent);
+		exit(1);
+	}
+	printf("%s: %u ms, %ld bytes\n", s->name, now, sent);
+	fclose(out);
+	ikcp_release(kcp[0]);
+	ikcp_release(kcp[1]);
+}
+
+int main(int argc, char *argv[])
+{
+	size_t i;
+	int j;
+	for (i = 0; i < sizeof(scenarios) / sizeof(scenarios[0]); i++) {
+		int selected = argc <= 1;
+		for (j = 1; j < argc; j++) {
+			if (strcmp(argv[j], scenarios[i].name) == 0) selected = 1;
+		}
+		if (selected) run(&scenarios[i]);
+	}
+	return 0;
+}